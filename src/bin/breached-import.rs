@@ -0,0 +1,20 @@
+//! Builds the day15 breached password corpus from a plain list, one
+//! password per line, merging it into any corpus already there.
+//!
+//! Usage: `breached-import [LIST]`, reading stdin when no list is given.
+//! The corpus goes to `DAY15_BREACHED_FILE`, as for the server.
+
+use cch23_adas::breached::{BreachedCorpus, DEFAULT_BREACHED_FILE};
+use std::{fs::File, io::BufReader, path::PathBuf};
+
+fn main() -> anyhow::Result<()> {
+    let path =
+        std::env::var("DAY15_BREACHED_FILE").unwrap_or_else(|_| DEFAULT_BREACHED_FILE.into());
+    let corpus = BreachedCorpus::new(PathBuf::from(&path));
+    let imported = match std::env::args_os().nth(1) {
+        Some(list) => corpus.import(BufReader::new(File::open(list)?))?,
+        None => corpus.import(std::io::stdin().lock())?,
+    };
+    println!("Imported {} passwords into {}", imported, path);
+    Ok(())
+}
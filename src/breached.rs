@@ -0,0 +1,278 @@
+//! The breached password corpus behind day15: SHA-1 hashes with occurrence
+//! counts in the HIBP "ordered by hash" format (`HASH:COUNT` per line,
+//! uppercase hex). Lookups binary-search the file on disk, so serving never
+//! loads the corpus into memory. Imports hold the hashes of the list being
+//! imported, but stream the existing corpus. Shared with the
+//! `breached-import` command.
+
+use sha1::{Digest, Sha1};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// Where the corpus lives unless `DAY15_BREACHED_FILE` says otherwise.
+pub const DEFAULT_BREACHED_FILE: &str = "breached/pwned-passwords-sha1.txt";
+
+/// Once the search window is this small, lines are just scanned.
+const SCAN_WINDOW: u64 = 4096;
+
+pub struct BreachedCorpus {
+    path: PathBuf,
+}
+
+impl BreachedCorpus {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// How many times the password appears in the corpus, zero if never or
+    /// if there is no corpus yet.
+    pub fn count(&self, input: &str) -> io::Result<u64> {
+        let hash = to_hex_upper(&Sha1::digest(input.as_bytes()));
+        let Some(mut corpus) = self.open()? else {
+            return Ok(0);
+        };
+        Ok(match corpus.find(hash.as_bytes())? {
+            Some((found, count)) if found == hash => count,
+            _ => 0,
+        })
+    }
+
+    /// The k-anonymity view: every `SUFFIX:COUNT` whose hash starts with
+    /// the five hex digit prefix, as served by the HIBP range API. `None`
+    /// if the prefix isn't five hex digits.
+    pub fn range(&self, prefix: &str) -> io::Result<Option<String>> {
+        if prefix.len() != 5 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let prefix = prefix.to_ascii_uppercase();
+        let mut body = String::new();
+        if let Some(mut corpus) = self.open()? {
+            let mut entry = corpus.find(format!("{:0<40}", prefix).as_bytes())?;
+            while let Some((hash, count)) = entry.filter(|(hash, _)| hash.starts_with(&prefix)) {
+                writeln!(&mut body, "{}:{}", &hash[5..], count).expect("Unable to write to string");
+                entry = corpus.next_entry()?;
+            }
+        }
+        Ok(Some(body))
+    }
+
+    /// Hashes a plain list, one password per line, and merges it into the
+    /// corpus. The merged file is written next to the old one and renamed
+    /// over it, so lookups never see a partial corpus. Returns the number
+    /// of passwords read.
+    pub fn import(&self, list: impl BufRead) -> io::Result<usize> {
+        let mut imported = Vec::new();
+        for line in list.split(b'\n') {
+            let mut line = line?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if !line.is_empty() {
+                imported.push(to_hex_upper(&Sha1::digest(&line)));
+            }
+        }
+        let read = imported.len();
+        imported.sort_unstable();
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let partial = self.path.with_extension("partial");
+        let mut out = BufWriter::new(File::create(&partial)?);
+        let mut existing = self.open()?;
+        let mut next_existing = || existing.as_mut().map_or(Ok(None), SortedLines::next_entry);
+
+        let mut old = next_existing()?;
+        let mut imported = imported.into_iter().peekable();
+        while let Some(hash) = imported.next() {
+            let mut count = 1;
+            while imported.next_if_eq(&hash).is_some() {
+                count += 1;
+            }
+            while let Some((old_hash, old_count)) = &old {
+                if *old_hash > hash {
+                    break;
+                }
+                if *old_hash == hash {
+                    count += old_count;
+                } else {
+                    writeln!(out, "{}:{}", old_hash, old_count)?;
+                }
+                old = next_existing()?;
+            }
+            writeln!(out, "{}:{}", hash, count)?;
+        }
+        while let Some((old_hash, old_count)) = old {
+            writeln!(out, "{}:{}", old_hash, old_count)?;
+            old = next_existing()?;
+        }
+
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&partial, &self.path)?;
+        Ok(read)
+    }
+
+    fn open(&self) -> io::Result<Option<SortedLines>> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(Some(SortedLines {
+                len: file.metadata()?.len(),
+                reader: BufReader::new(file),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A corpus file opened for reading, sorted by hash.
+struct SortedLines {
+    reader: BufReader<File>,
+    len: u64,
+}
+
+impl SortedLines {
+    /// The first entry whose hash isn't less than `key`, leaving the reader
+    /// just after it.
+    fn find(&mut self, key: &[u8]) -> io::Result<Option<(String, u64)>> {
+        // `low` is always a line start with only smaller hashes before it,
+        // and the entry we want starts somewhere in `low..=high`.
+        let (mut low, mut high) = (0, self.len);
+        let mut line = Vec::new();
+        while high - low > SCAN_WINDOW {
+            let mid = low + (high - low) / 2;
+            self.reader.seek(SeekFrom::Start(mid - 1))?;
+            let start = mid - 1 + self.reader.read_until(b'\n', &mut line)? as u64;
+            if start >= high {
+                break;
+            }
+            line.clear();
+            let read = self.reader.read_until(b'\n', &mut line)? as u64;
+            if line_hash(&line) < key {
+                low = start + read;
+            } else {
+                high = start;
+            }
+            line.clear();
+        }
+
+        self.reader.seek(SeekFrom::Start(low))?;
+        while let Some(entry) = self.next_entry()? {
+            if entry.0.as_bytes() >= key {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_entry(&mut self) -> io::Result<Option<(String, u64)>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (hash, count) = line.split_once(':').unwrap_or((line, "1"));
+            return match count.parse() {
+                Ok(count) if hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    Ok(Some((hash.to_ascii_uppercase(), count)))
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed corpus line {:?}", line),
+                )),
+            };
+        }
+    }
+}
+
+fn line_hash(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .position(|&b| b == b':' || b.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+fn to_hex_upper(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(&mut hex, "{:02X}", byte).expect("Unable to write to string");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus(dir: &tempfile::TempDir) -> BreachedCorpus {
+        BreachedCorpus::new(dir.path().join("breached").join("corpus.txt"))
+    }
+
+    #[test]
+    fn missing_corpus_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = corpus(&dir);
+        assert_eq!(corpus.count("password").unwrap(), 0);
+        assert_eq!(corpus.range("5BAA6").unwrap().as_deref(), Some(""));
+        assert_eq!(corpus.range("5BAA").unwrap(), None);
+    }
+
+    #[test]
+    fn import_merges_counts_into_the_sorted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = corpus(&dir);
+        assert_eq!(
+            corpus
+                .import(&b"password\r\n\nhunter2\npassword"[..])
+                .unwrap(),
+            3
+        );
+        assert_eq!(corpus.import(&b"password\nletmein\n"[..]).unwrap(), 2);
+
+        assert_eq!(corpus.count("password").unwrap(), 3);
+        assert_eq!(corpus.count("hunter2").unwrap(), 1);
+        assert_eq!(corpus.count("letmein").unwrap(), 1);
+        assert_eq!(corpus.count("correct horse").unwrap(), 0);
+        // SHA-1("password") is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8.
+        assert_eq!(
+            corpus.range("5baa6").unwrap().as_deref(),
+            Some("1E4C9B93F3F0682250B6CF8331B7EE68FD8:3\n")
+        );
+
+        let file = std::fs::read_to_string(&corpus.path).unwrap();
+        let hashes: Vec<&str> = file.lines().map(|line| &line[..40]).collect();
+        let mut sorted = hashes.clone();
+        sorted.sort_unstable();
+        assert_eq!(hashes, sorted);
+        assert_eq!(hashes.len(), 3);
+    }
+
+    #[test]
+    fn lookups_binary_search_large_corpora() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = corpus(&dir);
+        let list: String = (0..5000).map(|i| format!("password{}\n", i)).collect();
+        corpus.import(list.as_bytes()).unwrap();
+        assert!(std::fs::metadata(&corpus.path).unwrap().len() > 50 * SCAN_WINDOW);
+
+        for i in (0..5000).step_by(97) {
+            assert_eq!(corpus.count(&format!("password{}", i)).unwrap(), 1);
+        }
+        assert_eq!(corpus.count("password5000").unwrap(), 0);
+        let hash = to_hex_upper(&Sha1::digest(b"password42"));
+        let range = corpus.range(&hash[..5]).unwrap().unwrap();
+        assert!(range
+            .lines()
+            .any(|line| line == format!("{}:1", &hash[5..])));
+    }
+}
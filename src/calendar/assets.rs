@@ -0,0 +1,453 @@
+use axum::{
+    extract::{Path, State},
+    headers::{
+        AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt,
+        IfModifiedSince, IfNoneMatch, IfRange, LastModified,
+    },
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::SeekFrom,
+    path::{Component, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const DEFAULT_ASSETS_DIR: &str = "assets";
+
+/// Precompressed siblings, in order of preference.
+const ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+#[derive(Debug)]
+struct AssetsState {
+    root: PathBuf,
+    etags: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>>,
+}
+
+pub fn router() -> Router {
+    let root = std::env::var("ASSETS_DIR").unwrap_or_else(|_| DEFAULT_ASSETS_DIR.to_string());
+    let state = Arc::new(AssetsState {
+        root: PathBuf::from(root),
+        etags: Mutex::new(HashMap::new()),
+    });
+    Router::new()
+        .route("/assets", get(index_root))
+        .route("/assets/", get(index_root))
+        .route("/assets/*path", get(serve_asset))
+        .route_layer(middleware::from_fn(reject_traversal))
+        .with_state(state)
+}
+
+/// Refuses any request whose decoded path could step outside the assets
+/// directory, before it reaches the handlers.
+async fn reject_traversal<B>(request: Request<B>, next: Next<B>) -> Response {
+    if is_suspicious(request.uri().path()) {
+        return (StatusCode::BAD_REQUEST, "Invalid asset path").into_response();
+    }
+    next.run(request).await
+}
+
+/// Paths that don't decode cleanly, or that hold a `..` segment, a
+/// backslash or a NUL once decoded.
+fn is_suspicious(path: &str) -> bool {
+    percent_decode(path).map_or(true, |p| {
+        p.contains('\0') || p.contains('\\') || p.split('/').any(|s| s == "..")
+    })
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Maps a request path onto the assets directory, accepting only plain
+/// path segments and making sure symlinks don't lead outside the root.
+fn resolve(state: &AssetsState, requested: &str) -> Option<PathBuf> {
+    let relative = PathBuf::from(requested.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    let root = state.root.canonicalize().ok()?;
+    let resolved = root.join(relative).canonicalize().ok()?;
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+#[derive(Serialize, Debug)]
+struct IndexEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: u64,
+    modified: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DirectoryIndex {
+    path: String,
+    entries: Vec<IndexEntry>,
+}
+
+async fn index_root(State(state): State<Arc<AssetsState>>) -> Response {
+    match resolve(&state, "") {
+        Some(dir) => directory_index(&dir, "").await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn directory_index(dir: &std::path::Path, requested: &str) -> Response {
+    let Ok(mut read_dir) = tokio::fs::read_dir(dir).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut entries = Vec::new();
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Hidden files and precompressed variants are implementation details.
+        if name.starts_with('.')
+            || ENCODINGS.iter().any(|(_, ext)| {
+                name.strip_suffix(&format!(".{}", ext))
+                    .is_some_and(|original| dir.join(original).exists())
+            })
+        {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(IndexEntry {
+            name,
+            kind: if metadata.is_dir() { "dir" } else { "file" },
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata
+                .modified()
+                .ok()
+                .map(|m| DateTime::<Utc>::from(m).to_rfc3339()),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let path = format!("/assets/{}", requested.trim_matches('/'));
+    Json(DirectoryIndex { path, entries }).into_response()
+}
+
+/// Strong ETag over the file contents, cached until its size or mtime changes.
+async fn etag_for(
+    state: &AssetsState,
+    path: &std::path::Path,
+    len: u64,
+    modified: SystemTime,
+) -> std::io::Result<String> {
+    if let Some((cached_len, cached_modified, etag)) = state.etags.lock().unwrap().get(path) {
+        if *cached_len == len && *cached_modified == modified {
+            return Ok(etag.clone());
+        }
+    }
+
+    let contents = tokio::fs::read(path).await?;
+    let digest = Sha256::digest(&contents);
+    let mut etag = String::from("\"");
+    for byte in &digest[..16] {
+        write!(&mut etag, "{:02x}", byte).expect("Unable to write to string");
+    }
+    etag.push('"');
+
+    state
+        .etags
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (len, modified, etag.clone()));
+    Ok(etag)
+}
+
+/// Parses a single `bytes=` range into inclusive offsets. `None` means
+/// the header should be ignored (malformed, or several ranges) and the
+/// full body sent; `Err` means a 416. Nothing in an empty file can be
+/// satisfied.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let last = len.checked_sub(1);
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => {
+            let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+            // A range ending before it starts is invalid, not unsatisfiable.
+            if end < start {
+                return None;
+            }
+            (start, last.map(|last| end.min(last)))
+        }
+    };
+    match end {
+        Some(end) if start <= end => Some(Ok((start, end))),
+        _ => Some(Err(())),
+    }
+}
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|candidate| {
+            let mut parts = candidate.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let rejected = parts.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            name.eq_ignore_ascii_case(encoding) && !rejected
+        })
+}
+
+async fn serve_asset(
+    State(state): State<Arc<AssetsState>>,
+    Path(requested): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(path) = resolve(&state, &requested) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if path.is_dir() {
+        return directory_index(&path, &requested).await;
+    }
+
+    // Prefer a precompressed sibling when the client accepts it.
+    let mut content_encoding = None;
+    let mut body_path = path.clone();
+    for (encoding, ext) in ENCODINGS {
+        let mut candidate = path.clone().into_os_string();
+        candidate.push(format!(".{}", ext));
+        let candidate = PathBuf::from(candidate);
+        if accepts_encoding(&headers, encoding) && candidate.is_file() {
+            content_encoding = Some(encoding);
+            body_path = candidate;
+            break;
+        }
+    }
+
+    let Ok(metadata) = tokio::fs::metadata(&body_path).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let len = metadata.len();
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let Ok(etag) = etag_for(&state, &body_path, len, modified).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let etag = etag.parse::<ETag>().expect("well-formed etag");
+    let last_modified = LastModified::from(modified);
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.typed_insert(etag.clone());
+    response_headers.typed_insert(last_modified);
+    response_headers.typed_insert(AcceptRanges::bytes());
+    response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response_headers.typed_insert(ContentType::from(
+        mime_guess::from_path(&path).first_or_octet_stream(),
+    ));
+    if let Some(encoding) = content_encoding {
+        response_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since.
+    let not_modified = match headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+        None => headers
+            .typed_get::<IfModifiedSince>()
+            .is_some_and(|since| !since.is_modified(modified)),
+    };
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+
+    let range_applies = headers.typed_get::<IfRange>().map_or(true, |if_range| {
+        !if_range.is_modified(Some(&etag), Some(&last_modified))
+    });
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| range_applies)
+        .and_then(|v| parse_range(v, len));
+
+    let Ok(mut file) = tokio::fs::File::open(&body_path).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match range {
+        Some(Err(())) => {
+            response_headers.typed_insert(ContentRange::unsatisfied_bytes(len));
+            (StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response()
+        }
+        Some(Ok((start, end))) => {
+            let mut body = vec![0; (end - start + 1) as usize];
+            if file.seek(SeekFrom::Start(start)).await.is_err()
+                || file.read_exact(&mut body).await.is_err()
+            {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            response_headers.typed_insert(
+                ContentRange::bytes(start..=end, len).expect("range within file length"),
+            );
+            response_headers.typed_insert(ContentLength(body.len() as u64));
+            (StatusCode::PARTIAL_CONTENT, response_headers, body).into_response()
+        }
+        None => {
+            let mut body = Vec::with_capacity(len as usize);
+            if file.read_to_end(&mut body).await.is_err() {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            (StatusCode::OK, response_headers, body).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_in(root: &std::path::Path) -> AssetsState {
+        AssetsState {
+            root: root.to_path_buf(),
+            etags: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("/a%20b").as_deref(), Some("/a b"));
+        assert_eq!(percent_decode("%2e%2E").as_deref(), Some(".."));
+        assert_eq!(percent_decode("a%2fb").as_deref(), Some("a/b"));
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn traversal_is_rejected_however_it_is_encoded() {
+        for path in [
+            "/assets/../Cargo.toml",
+            "/assets/%2e%2e/Cargo.toml",
+            "/assets/%2E%2e/Cargo.toml",
+            "/assets/.%2e/Cargo.toml",
+            "/assets/a%2f..%2f..%2fCargo.toml",
+            "/assets/..%5cCargo.toml",
+            "/assets/a\\b",
+            "/assets/a%00.png",
+            "/assets/%zz",
+            "/assets/..",
+        ] {
+            assert!(is_suspicious(path), "{} should be rejected", path);
+        }
+        for path in [
+            "/assets",
+            "/assets/",
+            "/assets/a/b.png",
+            "/assets/..a",
+            "/assets/a..b",
+        ] {
+            assert!(!is_suspicious(path), "{} should be allowed", path);
+        }
+    }
+
+    #[test]
+    fn resolve_stays_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("assets");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/file.txt"), "hi").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "no").unwrap();
+        let state = state_in(&root);
+
+        let inside = resolve(&state, "sub/file.txt").unwrap();
+        assert!(inside.ends_with("sub/file.txt"));
+        assert!(resolve(&state, "/sub/./file.txt").is_some());
+        assert!(resolve(&state, "").is_some());
+        assert!(resolve(&state, "../secret.txt").is_none());
+        assert!(resolve(&state, "sub/../../secret.txt").is_none());
+        assert!(resolve(&state, "//etc/passwd").is_none());
+        assert!(resolve(&state, "missing.txt").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_refuses_symlinks_out_of_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("assets");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "no").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), root.join("up")).unwrap();
+        let state = state_in(&root);
+
+        assert!(resolve(&state, "link").is_none());
+        assert!(resolve(&state, "up/secret.txt").is_none());
+    }
+
+    #[test]
+    fn parse_range_covers_the_range_forms() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(parse_range(" bytes=10-10 ", 100), Some(Ok((10, 10))));
+        // Open-ended and suffix ranges.
+        assert_eq!(parse_range("bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Some(Ok((0, 99))));
+        // An end past the file is clamped; a start past it can't be served.
+        assert_eq!(parse_range("bytes=50-500", 100), Some(Ok((50, 99))));
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=100-200", 100), Some(Err(())));
+        // Zero-length suffixes and empty files are unsatisfiable.
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-5", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(Err(())));
+        // Anything malformed is ignored in favour of the full body.
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+        assert_eq!(parse_range("bytes=-", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+        assert_eq!(parse_range("bytes=5", 100), None);
+    }
+}
@@ -1,42 +1,961 @@
-use axum::{extract::Multipart, response::IntoResponse, routing::post, Router};
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Multipart, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
 use tower_http::services::ServeFile;
 
+const DEFAULT_PALETTE_SIZE: usize = 5;
+const MAX_PALETTE_SIZE: usize = 16;
+const KMEANS_ITERATIONS: usize = 10;
+const KMEANS_MAX_SAMPLES: usize = 10_000;
+const MAX_OUTPUT_DIMENSION: u32 = 4096;
+const JPEG_QUALITY: u8 = 85;
+
+const DEFAULT_MAX_FILE_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 32 * 1024 * 1024;
+const DEFAULT_MAX_MEGAPIXELS: f64 = 40.0;
+
+/// Upload limits, overridable through `DAY11_MAX_FILE_BYTES`,
+/// `DAY11_MAX_REQUEST_BYTES` and `DAY11_MAX_MEGAPIXELS`.
+#[derive(Debug, Clone, Copy)]
+struct UploadLimits {
+    max_file_bytes: usize,
+    max_request_bytes: usize,
+    max_megapixels: f64,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+impl UploadLimits {
+    fn from_env() -> Self {
+        Self {
+            max_file_bytes: env_or("DAY11_MAX_FILE_BYTES", DEFAULT_MAX_FILE_BYTES),
+            max_request_bytes: env_or("DAY11_MAX_REQUEST_BYTES", DEFAULT_MAX_REQUEST_BYTES),
+            max_megapixels: env_or("DAY11_MAX_MEGAPIXELS", DEFAULT_MAX_MEGAPIXELS),
+        }
+    }
+}
+
 pub fn router() -> Router {
+    let limits = UploadLimits::from_env();
     Router::new()
         .route("/11/red_pixels", post(analyze_pixels))
+        .route("/11/analyze", post(analyze_image))
+        .route("/11/mask", post(mask_image))
+        .layer(DefaultBodyLimit::max(limits.max_request_bytes))
+        .with_state(limits)
         .nest_service(
             "/11/assets/decoration.png",
             ServeFile::new("assets/decoration.png"),
         )
 }
 
-async fn analyze_pixels(mut files: Multipart) -> impl IntoResponse {
-    match files.next_field().await.unwrap() {
-        Some(field) => {
-            let _name = field.name().unwrap().to_string();
-            let data = field.bytes().await.unwrap();
-            let image = image::io::Reader::new(std::io::Cursor::new(data.clone()))
-                .with_guessed_format()
-                .unwrap()
-                .decode()
-                .unwrap();
-            let total_magic_red =
-                image
-                    .into_rgba8()
-                    .enumerate_pixels()
-                    .fold(0, |acc, (_x, _y, pixel)| {
-                        let [r, g, b, _] = pixel.0;
-                        if r as usize > b as usize + g as usize {
-                            acc + 1
-                        } else {
-                            acc
-                        }
-                    });
-            return total_magic_red.to_string().into_response();
-        }
-        None => {
-            println!("No file uploaded");
-            return "422".into_response();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Term {
+    Channel(Channel),
+    Constant(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+}
+
+/// Selects which pixels count as a match, either by comparing sums of
+/// channels (`green > red + blue`) or by an HSV range
+/// (`hsv(200..260, 0.5..1, 0.2..1)`, hue in degrees, the rest in 0..1).
+///
+/// In a query string a literal `+` decodes to a space, so terms may be
+/// separated by either: `?predicate=green%3Ered+blue` and
+/// `?predicate=green%3Ered%2Bblue` mean the same thing.
+#[derive(Debug, Clone, PartialEq)]
+enum ColorPredicate {
+    Channels {
+        lhs: Vec<Term>,
+        comparison: Comparison,
+        rhs: Vec<Term>,
+    },
+    Hsv {
+        hue: (f32, f32),
+        saturation: (f32, f32),
+        value: (f32, f32),
+    },
+}
+
+impl Default for ColorPredicate {
+    fn default() -> Self {
+        Self::Channels {
+            lhs: vec![Term::Channel(Channel::Red)],
+            comparison: Comparison::Greater,
+            rhs: vec![Term::Channel(Channel::Green), Term::Channel(Channel::Blue)],
+        }
+    }
+}
+
+impl FromStr for Term {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "r" | "red" => Ok(Term::Channel(Channel::Red)),
+            "g" | "green" => Ok(Term::Channel(Channel::Green)),
+            "b" | "blue" => Ok(Term::Channel(Channel::Blue)),
+            "a" | "alpha" => Ok(Term::Channel(Channel::Alpha)),
+            other => other
+                .parse::<i64>()
+                .map(Term::Constant)
+                .map_err(|_| format!("unknown term '{}'", other)),
+        }
+    }
+}
+
+fn parse_sum(input: &str) -> Result<Vec<Term>, String> {
+    if input.trim().is_empty() {
+        return Err("empty side in predicate".to_string());
+    }
+    input
+        .split(|c: char| c == '+' || c.is_whitespace())
+        .filter(|term| !term.is_empty())
+        .map(Term::from_str)
+        .collect()
+}
+
+fn parse_range(input: &str) -> Result<(f32, f32), String> {
+    let (low, high) = input
+        .split_once("..")
+        .ok_or_else(|| format!("expected a range like 'low..high', got '{}'", input.trim()))?;
+    let low = low
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("invalid range bound '{}'", low.trim()))?;
+    let high = high
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("invalid range bound '{}'", high.trim()))?;
+    Ok((low, high))
+}
+
+impl FromStr for ColorPredicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(args) = s
+            .strip_prefix("hsv(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            let ranges = args
+                .split(',')
+                .map(parse_range)
+                .collect::<Result<Vec<_>, _>>()?;
+            let [hue, saturation, value] = ranges[..] else {
+                return Err("hsv() takes exactly three ranges".to_string());
+            };
+            return Ok(Self::Hsv {
+                hue,
+                saturation,
+                value,
+            });
+        }
+
+        // Longer operators first so ">=" isn't read as ">".
+        let operators = [
+            (">=", Comparison::GreaterOrEqual),
+            ("<=", Comparison::LessOrEqual),
+            ("==", Comparison::Equal),
+            (">", Comparison::Greater),
+            ("<", Comparison::Less),
+            ("=", Comparison::Equal),
+        ];
+        for (token, comparison) in operators {
+            if let Some((lhs, rhs)) = s.split_once(token) {
+                return Ok(Self::Channels {
+                    lhs: parse_sum(lhs)?,
+                    comparison,
+                    rhs: parse_sum(rhs)?,
+                });
+            }
+        }
+        Err(format!("no comparison operator in predicate '{}'", s))
+    }
+}
+
+fn sum_terms(terms: &[Term], pixel: [u8; 4]) -> i64 {
+    terms
+        .iter()
+        .map(|term| match term {
+            Term::Channel(Channel::Red) => pixel[0] as i64,
+            Term::Channel(Channel::Green) => pixel[1] as i64,
+            Term::Channel(Channel::Blue) => pixel[2] as i64,
+            Term::Channel(Channel::Alpha) => pixel[3] as i64,
+            Term::Constant(c) => *c,
+        })
+        .sum()
+}
+
+fn rgb_to_hsv(pixel: [u8; 4]) -> (f32, f32, f32) {
+    let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+fn in_range(value: f32, (low, high): (f32, f32)) -> bool {
+    value >= low && value <= high
+}
+
+impl ColorPredicate {
+    fn matches(&self, pixel: [u8; 4]) -> bool {
+        match self {
+            Self::Channels {
+                lhs,
+                comparison,
+                rhs,
+            } => {
+                let (lhs, rhs) = (sum_terms(lhs, pixel), sum_terms(rhs, pixel));
+                match comparison {
+                    Comparison::Greater => lhs > rhs,
+                    Comparison::GreaterOrEqual => lhs >= rhs,
+                    Comparison::Less => lhs < rhs,
+                    Comparison::LessOrEqual => lhs <= rhs,
+                    Comparison::Equal => lhs == rhs,
+                }
+            }
+            Self::Hsv {
+                hue,
+                saturation,
+                value,
+            } => {
+                let (h, s, v) = rgb_to_hsv(pixel);
+                // A hue range like 330..30 wraps around red.
+                let hue_matches = if hue.0 <= hue.1 {
+                    in_range(h, *hue)
+                } else {
+                    h >= hue.0 || h <= hue.1
+                };
+                hue_matches && in_range(s, *saturation) && in_range(v, *value)
+            }
+        }
+    }
+}
+
+fn count_matching(image: &RgbaImage, predicate: &ColorPredicate) -> usize {
+    image
+        .pixels()
+        .filter(|pixel| predicate.matches(pixel.0))
+        .count()
+}
+
+/// A single upload keeps the plain-text count; several uploads answer with
+/// a JSON object of counts keyed by file name.
+async fn analyze_pixels(
+    State(limits): State<UploadLimits>,
+    mut files: Multipart,
+) -> Result<Response, (StatusCode, String)> {
+    let uploads = read_uploads(&mut files, &limits).await?;
+    let counts = blocking(move || {
+        let mut counts = BTreeMap::new();
+        for upload in &uploads {
+            let image = decode_upload(upload, &limits)?.into_rgba8();
+            counts.insert(
+                upload.name.clone(),
+                count_matching(&image, &ColorPredicate::default()),
+            );
+        }
+        Ok(counts)
+    })
+    .await?;
+
+    match counts.len() {
+        1 => Ok(counts
+            .into_values()
+            .sum::<usize>()
+            .to_string()
+            .into_response()),
+        _ => Ok(Json(counts).into_response()),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AnalyzeQuery {
+    predicate: Option<String>,
+    palette: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct Histograms {
+    red: Vec<u64>,
+    green: Vec<u64>,
+    blue: Vec<u64>,
+    alpha: Vec<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct PaletteColor {
+    color: String,
+    share: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct ImageAnalysis {
+    width: u32,
+    height: u32,
+    pixels: u64,
+    matching: usize,
+    histogram: Histograms,
+    palette: Vec<PaletteColor>,
+    mean_brightness: f64,
+    alpha_coverage: f64,
+}
+
+fn histograms(image: &RgbaImage) -> Histograms {
+    let mut channels = [[0u64; 256]; 4];
+    for pixel in image.pixels() {
+        for (channel, value) in channels.iter_mut().zip(pixel.0) {
+            channel[value as usize] += 1;
+        }
+    }
+    let [red, green, blue, alpha] = channels.map(|c| c.to_vec());
+    Histograms {
+        red,
+        green,
+        blue,
+        alpha,
+    }
+}
+
+fn luma(pixel: [u8; 4]) -> f64 {
+    0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64
+}
+
+fn squared_distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+fn nearest_centroid(point: [f64; 3], centroids: &[[f64; 3]]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            squared_distance(point, **a).total_cmp(&squared_distance(point, **b))
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// Dominant colours via k-means over a strided sample of the visible pixels.
+/// Seeding starts from the median-brightness sample and adds whichever
+/// sample is farthest from the centroids so far, so the result is
+/// deterministic and no two centroids start on the same colour.
+fn dominant_colors(image: &RgbaImage, k: usize) -> Vec<PaletteColor> {
+    let visible = image.pixels().filter(|p| p.0[3] > 0).count();
+    let stride = (visible / KMEANS_MAX_SAMPLES).max(1);
+    let mut samples: Vec<[f64; 3]> = image
+        .pixels()
+        .filter(|p| p.0[3] > 0)
+        .step_by(stride)
+        .map(|p| [p.0[0] as f64, p.0[1] as f64, p.0[2] as f64])
+        .collect();
+    if samples.is_empty() || k == 0 {
+        return Vec::new();
+    }
+    samples.sort_by(|a, b| a.iter().sum::<f64>().total_cmp(&b.iter().sum::<f64>()));
+
+    let mut centroids = vec![samples[samples.len() / 2]];
+    while centroids.len() < k {
+        let distance = |sample: &[f64; 3]| {
+            centroids
+                .iter()
+                .map(|centroid| squared_distance(*sample, *centroid))
+                .fold(f64::INFINITY, f64::min)
+        };
+        let farthest = samples
+            .iter()
+            .max_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .expect("samples is not empty");
+        // Fewer distinct colours than requested.
+        if distance(farthest) == 0.0 {
+            break;
+        }
+        centroids.push(*farthest);
+    }
+    let k = centroids.len();
+    let mut assignments = vec![0usize; samples.len()];
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest_centroid(*sample, &centroids);
+            if nearest != *assignment {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![([0f64; 3], 0usize); k];
+        for (sample, assignment) in samples.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[*assignment];
+            sum.iter_mut().zip(sample).for_each(|(s, v)| *s += v);
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|s| s / count as f64);
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut counts = vec![0usize; k];
+    assignments.iter().for_each(|a| counts[*a] += 1);
+    let mut palette: Vec<PaletteColor> = centroids
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(centroid, count)| {
+            let [r, g, b] = centroid.map(|c| c.round().clamp(0.0, 255.0) as u8);
+            PaletteColor {
+                color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+                share: count as f64 / samples.len() as f64,
+            }
+        })
+        .collect();
+    palette.sort_by(|a, b| b.share.total_cmp(&a.share));
+    palette
+}
+
+fn analyze(image: &RgbaImage, predicate: &ColorPredicate, palette_size: usize) -> ImageAnalysis {
+    let pixels = image.width() as u64 * image.height() as u64;
+    let (brightness, opaque) = image.pixels().fold((0f64, 0u64), |(sum, opaque), p| {
+        (sum + luma(p.0), opaque + u64::from(p.0[3] > 0))
+    });
+    let ratio = |n: f64| if pixels == 0 { 0.0 } else { n / pixels as f64 };
+
+    ImageAnalysis {
+        width: image.width(),
+        height: image.height(),
+        pixels,
+        matching: count_matching(image, predicate),
+        histogram: histograms(image),
+        palette: dominant_colors(image, palette_size),
+        mean_brightness: ratio(brightness / 255.0),
+        alpha_coverage: ratio(opaque as f64),
+    }
+}
+
+/// Decoding and pixel work is CPU-bound, so it runs on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, (StatusCode, String)> + Send + 'static,
+) -> Result<T, (StatusCode, String)> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+}
+
+#[derive(Debug)]
+struct Upload {
+    name: String,
+    data: Bytes,
+}
+
+/// Reads every file field, keyed by its file name (or field name), and
+/// stops streaming a field as soon as it exceeds the per-file byte limit.
+async fn read_uploads(
+    files: &mut Multipart,
+    limits: &UploadLimits,
+) -> Result<Vec<Upload>, (StatusCode, String)> {
+    let mut uploads: Vec<Upload> = Vec::new();
+    while let Some(mut field) = files
+        .next_field()
+        .await
+        .map_err(|e| (e.status(), e.body_text()))?
+    {
+        let base = field
+            .file_name()
+            .or_else(|| field.name())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("file{}", uploads.len()));
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while uploads.iter().any(|u| u.name == name) {
+            suffix += 1;
+            name = format!("{}#{}", base, suffix);
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field
+            .chunk()
+            .await
+            .map_err(|e| (e.status(), e.body_text()))?
+        {
+            if data.len() + chunk.len() > limits.max_file_bytes {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "'{}' exceeds the limit of {} bytes per file",
+                        name, limits.max_file_bytes
+                    ),
+                ));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        uploads.push(Upload {
+            name,
+            data: Bytes::from(data),
+        });
+    }
+
+    if uploads.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "No file uploaded".to_string(),
+        ));
+    }
+    Ok(uploads)
+}
+
+/// Checks the format and dimensions from the image header before paying
+/// for a full decode.
+fn decode_upload(
+    upload: &Upload,
+    limits: &UploadLimits,
+) -> Result<DynamicImage, (StatusCode, String)> {
+    let reader = || {
+        image::io::Reader::new(std::io::Cursor::new(&upload.data[..]))
+            .with_guessed_format()
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("'{}' is unreadable: {}", upload.name, e),
+                )
+            })
+    };
+
+    if reader()?.format().is_none() {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("'{}' is not a recognised image format", upload.name),
+        ));
+    }
+    let (width, height) = reader()?.into_dimensions().map_err(|e| {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("'{}' has an unreadable header: {}", upload.name, e),
+        )
+    })?;
+    let megapixels = width as f64 * height as f64 / 1_000_000.0;
+    if megapixels > limits.max_megapixels {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "'{}' is {}x{} ({:.1} MP), over the limit of {} MP",
+                upload.name, width, height, megapixels, limits.max_megapixels
+            ),
+        ));
+    }
+
+    reader()?.decode().map_err(|e| {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("'{}' could not be decoded: {}", upload.name, e),
+        )
+    })
+}
+
+fn parse_predicate(predicate: Option<&str>) -> Result<ColorPredicate, (StatusCode, String)> {
+    match predicate {
+        Some(p) => p
+            .parse::<ColorPredicate>()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid predicate: {}", e))),
+        None => Ok(ColorPredicate::default()),
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum AnalysisResponse {
+    Single(ImageAnalysis),
+    PerFile(BTreeMap<String, ImageAnalysis>),
+}
+
+async fn analyze_image(
+    State(limits): State<UploadLimits>,
+    Query(query): Query<AnalyzeQuery>,
+    mut files: Multipart,
+) -> Result<Json<AnalysisResponse>, (StatusCode, String)> {
+    let predicate = parse_predicate(query.predicate.as_deref())?;
+    let palette_size = query
+        .palette
+        .unwrap_or(DEFAULT_PALETTE_SIZE)
+        .min(MAX_PALETTE_SIZE);
+
+    let uploads = read_uploads(&mut files, &limits).await?;
+    let mut results = blocking(move || {
+        let mut results = BTreeMap::new();
+        for upload in &uploads {
+            let image = decode_upload(upload, &limits)?.into_rgba8();
+            results.insert(
+                upload.name.clone(),
+                analyze(&image, &predicate, palette_size),
+            );
+        }
+        Ok(results)
+    })
+    .await?;
+
+    if results.len() == 1 {
+        let (_, analysis) = results.pop_first().expect("one result");
+        return Ok(Json(AnalysisResponse::Single(analysis)));
+    }
+    Ok(Json(AnalysisResponse::PerFile(results)))
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct MaskQuery {
+    predicate: Option<String>,
+    highlight: Option<String>,
+    crop: Option<String>,
+    rotate: Option<u32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "webp" => Ok(Self::WebP),
+            "gif" => Ok(Self::Gif),
+            other => Err(format!("unsupported output format '{}'", other)),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+            Self::Gif => "image/gif",
+        }
+    }
+
+    fn image_format(self) -> ImageOutputFormat {
+        match self {
+            Self::Png => ImageOutputFormat::Png,
+            Self::Jpeg => ImageOutputFormat::Jpeg(JPEG_QUALITY),
+            Self::WebP => ImageOutputFormat::WebP,
+            Self::Gif => ImageOutputFormat::Gif,
+        }
+    }
+}
+
+fn parse_color(input: &str) -> Result<[u8; 3], String> {
+    let hex = input.trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected a colour like '#ff00ff', got '{}'", input));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Crop, rotate and resize are applied in that order, before masking.
+fn transform(
+    image: DynamicImage,
+    query: &MaskQuery,
+    limits: &UploadLimits,
+) -> Result<DynamicImage, String> {
+    let mut image = image;
+
+    if let Some(crop) = &query.crop {
+        let parts = crop
+            .split(',')
+            .map(|p| p.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid crop '{}', expected x,y,width,height", crop))?;
+        let [x, y, width, height] = parts[..] else {
+            return Err(format!(
+                "invalid crop '{}', expected x,y,width,height",
+                crop
+            ));
+        };
+        if width == 0
+            || height == 0
+            || x.saturating_add(width) > image.width()
+            || y.saturating_add(height) > image.height()
+        {
+            return Err(format!(
+                "crop '{}' is outside the {}x{} image",
+                crop,
+                image.width(),
+                image.height()
+            ));
+        }
+        image = image.crop_imm(x, y, width, height);
+    }
+
+    image = match query.rotate.unwrap_or(0) % 360 {
+        0 => image,
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        other => return Err(format!("rotation must be a multiple of 90, got {}", other)),
+    };
+
+    // Work out the full output size first: with only one side given, the
+    // other follows the aspect ratio and can be far larger.
+    let (width, height) = (u64::from(image.width()), u64::from(image.height()));
+    let scaled = |side: u32, from: u64, to: u64| (u64::from(side) * to + from / 2) / from;
+    let (out_width, out_height) = match (query.width, query.height) {
+        (Some(0), _) | (_, Some(0)) => return Err("resize dimensions must be positive".into()),
+        (Some(w), Some(h)) => (u64::from(w), u64::from(h)),
+        (Some(w), None) => (u64::from(w), scaled(w, width, height).max(1)),
+        (None, Some(h)) => (scaled(h, height, width).max(1), u64::from(h)),
+        (None, None) => return Ok(image),
+    };
+    if out_width > u64::from(MAX_OUTPUT_DIMENSION) || out_height > u64::from(MAX_OUTPUT_DIMENSION) {
+        return Err(format!(
+            "resized image would be {}x{}, sides cannot exceed {}",
+            out_width, out_height, MAX_OUTPUT_DIMENSION
+        ));
+    }
+    let megapixels = (out_width * out_height) as f64 / 1_000_000.0;
+    if megapixels > limits.max_megapixels {
+        return Err(format!(
+            "resized image would be {:.1} megapixels, the limit is {}",
+            megapixels, limits.max_megapixels
+        ));
+    }
+
+    Ok(image.resize_exact(out_width as u32, out_height as u32, FilterType::Triangle))
+}
+
+/// Keeps matching pixels (or paints them with `highlight`) and turns the
+/// rest into faded greyscale so the matches stand out.
+fn mask(image: &RgbaImage, predicate: &ColorPredicate, highlight: Option<[u8; 3]>) -> RgbaImage {
+    let mut masked = image.clone();
+    for pixel in masked.pixels_mut() {
+        let a = pixel.0[3];
+        if predicate.matches(pixel.0) {
+            if let Some([r, g, b]) = highlight {
+                pixel.0 = [r, g, b, a];
+            }
+        } else {
+            let grey = (luma(pixel.0) / 2.0 + 127.5) as u8;
+            pixel.0 = [grey, grey, grey, a];
         }
     }
+    masked
+}
+
+/// Masks a single uploaded file.
+async fn mask_image(
+    State(limits): State<UploadLimits>,
+    Query(query): Query<MaskQuery>,
+    mut files: Multipart,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let predicate = parse_predicate(query.predicate.as_deref())?;
+    let highlight = query
+        .highlight
+        .as_deref()
+        .map(parse_color)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid highlight: {}", e)))?;
+    let format = query
+        .format
+        .as_deref()
+        .map(OutputFormat::from_str)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?
+        .unwrap_or(OutputFormat::Png);
+
+    let mut uploads = read_uploads(&mut files, &limits).await?;
+    if uploads.len() > 1 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Upload a single file to mask, not {}", uploads.len()),
+        ));
+    }
+    let upload = uploads.remove(0);
+    let encoded = blocking(move || {
+        let image = transform(decode_upload(&upload, &limits)?, &query, &limits)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid transform: {}", e)))?;
+        let masked = DynamicImage::ImageRgba8(mask(&image.into_rgba8(), &predicate, highlight));
+        // JPEG has no alpha channel, so flatten before encoding.
+        let masked = match format {
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(masked.into_rgb8()),
+            _ => masked,
+        };
+
+        let mut encoded = std::io::Cursor::new(Vec::new());
+        masked
+            .write_to(&mut encoded, format.image_format())
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Could not encode image: {}", e),
+                )
+            })?;
+        Ok(encoded.into_inner())
+    })
+    .await?;
+
+    Ok(([(header::CONTENT_TYPE, format.content_type())], encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(predicate: &str) -> (Vec<Term>, Comparison, Vec<Term>) {
+        match predicate.parse::<ColorPredicate>().unwrap() {
+            ColorPredicate::Channels {
+                lhs,
+                comparison,
+                rhs,
+            } => (lhs, comparison, rhs),
+            other => panic!("{:?} parsed as {:?}", predicate, other),
+        }
+    }
+
+    #[test]
+    fn predicate_parse_errors() {
+        for predicate in [
+            "",
+            "red",
+            "red >",
+            "> blue",
+            "purple > red",
+            "red > 1.5",
+            "hsv(0..360, 0..1)",
+            "hsv(0..360, 0..1, 0..1, 0..1)",
+            "hsv(0..x, 0..1, 0..1)",
+            "hsv(10, 0..1, 0..1)",
+        ] {
+            assert!(
+                predicate.parse::<ColorPredicate>().is_err(),
+                "{:?} should not parse",
+                predicate
+            );
+        }
+    }
+
+    #[test]
+    fn predicate_operators_and_terms() {
+        use Channel::*;
+        // Two-character operators win over their one-character prefixes.
+        assert_eq!(channels("red >= green").1, Comparison::GreaterOrEqual);
+        assert_eq!(channels("red <= green").1, Comparison::LessOrEqual);
+        assert_eq!(channels("red == green").1, Comparison::Equal);
+        assert_eq!(channels("red = green").1, Comparison::Equal);
+        assert_eq!(channels("red < green").1, Comparison::Less);
+        // `+` binds within a side, and a space (a query-string `+`) does too.
+        for predicate in ["R+g > blue", "r g>b", "red + green > BLUE"] {
+            assert_eq!(
+                channels(predicate),
+                (
+                    vec![Term::Channel(Red), Term::Channel(Green)],
+                    Comparison::Greater,
+                    vec![Term::Channel(Blue)],
+                ),
+                "{:?}",
+                predicate
+            );
+        }
+        assert_eq!(
+            channels("alpha > 128 + -1").2,
+            vec![Term::Constant(128), Term::Constant(-1)]
+        );
+        assert_eq!(
+            "red > green + blue".parse::<ColorPredicate>().unwrap(),
+            ColorPredicate::default()
+        );
+    }
+
+    #[test]
+    fn predicates_match_pixels() {
+        let default = ColorPredicate::default();
+        assert!(default.matches([200, 50, 50, 255]));
+        assert!(!default.matches([100, 50, 50, 255]));
+        let reds = "hsv(330..30, 0.5..1, 0.2..1)"
+            .parse::<ColorPredicate>()
+            .unwrap();
+        assert!(reds.matches([255, 0, 0, 255]));
+        assert!(reds.matches([255, 0, 40, 255]));
+        assert!(!reds.matches([0, 255, 0, 255]));
+        assert!(!reds.matches([40, 0, 0, 255]));
+    }
+
+    #[test]
+    fn dominant_colors_finds_the_clusters() {
+        // A quarter red, three quarters blue, and a transparent row that
+        // doesn't count.
+        let image = RgbaImage::from_fn(8, 9, |x, y| match (x, y) {
+            (_, 8) => image::Rgba([0, 255, 0, 0]),
+            (0..=1, _) => image::Rgba([250, 10, 10, 255]),
+            _ => image::Rgba([10, 10, 250, 255]),
+        });
+        let palette = dominant_colors(&image, 2);
+        let summary: Vec<(&str, f64)> = palette
+            .iter()
+            .map(|color| (color.color.as_str(), color.share))
+            .collect();
+        assert_eq!(summary, [("#0a0afa", 0.75), ("#fa0a0a", 0.25)]);
+
+        assert_eq!(dominant_colors(&image, 1).len(), 1);
+        assert!(dominant_colors(&image, 0).is_empty());
+        let transparent = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 0]));
+        assert!(dominant_colors(&transparent, 3).is_empty());
+    }
 }
@@ -1,21 +1,261 @@
 use axum::{
-    extract::{Json, Path, State},
-    response::IntoResponse,
+    async_trait,
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use chrono::{DateTime, Datelike, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
-use ulid::Ulid;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+use ulid::{Generator, Ulid};
 use uuid::Uuid;
 
+use super::AppError;
+
+const DEFAULT_PACKET_TTL_SECS: u64 = 24 * 60 * 60;
+
 type AppData = HashMap<String, u64>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Where `save/:pkg_id` timestamps live. Entries older than the store's TTL
+/// are treated as unknown and purged when next touched.
+#[async_trait]
+trait PacketStore: Send + Sync + std::fmt::Debug {
+    async fn save(&self, pkg_id: &str, timestamp: u64) -> anyhow::Result<()>;
+    async fn load(&self, pkg_id: &str, now: u64) -> anyhow::Result<Option<u64>>;
+    async fn list(&self, now: u64) -> anyhow::Result<AppData>;
+}
+
+#[derive(Debug)]
+struct MemoryPacketStore {
+    ttl: u64,
+    packets: Mutex<AppData>,
+}
+
+impl MemoryPacketStore {
+    fn new(ttl: u64) -> Self {
+        Self {
+            ttl,
+            packets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl PacketStore for MemoryPacketStore {
+    async fn save(&self, pkg_id: &str, timestamp: u64) -> anyhow::Result<()> {
+        self.packets
+            .lock()
+            .unwrap()
+            .insert(pkg_id.to_string(), timestamp);
+        Ok(())
+    }
+
+    async fn load(&self, pkg_id: &str, now: u64) -> anyhow::Result<Option<u64>> {
+        Ok(self.list(now).await?.get(pkg_id).copied())
+    }
+
+    async fn list(&self, now: u64) -> anyhow::Result<AppData> {
+        let mut packets = self.packets.lock().unwrap();
+        packets.retain(|_, saved| now.saturating_sub(*saved) <= self.ttl);
+        Ok(packets.clone())
+    }
+}
+
+#[derive(Debug)]
+struct PgPacketStore {
+    ttl: u64,
+    pool: PgPool,
+    schema: OnceCell<()>,
+}
+
+impl PgPacketStore {
+    fn new(pool: PgPool, ttl: u64) -> Self {
+        Self {
+            ttl,
+            pool,
+            schema: OnceCell::new(),
+        }
+    }
+
+    async fn ensure_schema(&self) -> anyhow::Result<()> {
+        self.schema
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "CREATE TABLE IF NOT EXISTS packets (
+                        id TEXT PRIMARY KEY,
+                        saved_at BIGINT NOT NULL
+                    );",
+                )
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: u64) -> anyhow::Result<()> {
+        self.ensure_schema().await?;
+        sqlx::query("DELETE FROM packets WHERE saved_at < $1")
+            .bind(now.saturating_sub(self.ttl) as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PacketStore for PgPacketStore {
+    async fn save(&self, pkg_id: &str, timestamp: u64) -> anyhow::Result<()> {
+        self.ensure_schema().await?;
+        sqlx::query(
+            "INSERT INTO packets (id, saved_at)
+            VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET saved_at = EXCLUDED.saved_at",
+        )
+        .bind(pkg_id)
+        .bind(timestamp as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self, pkg_id: &str, now: u64) -> anyhow::Result<Option<u64>> {
+        self.purge_expired(now).await?;
+        let row = sqlx::query("SELECT saved_at FROM packets WHERE id = $1")
+            .bind(pkg_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|r| r.get::<i64, _>("saved_at") as u64))
+    }
+
+    async fn list(&self, now: u64) -> anyhow::Result<AppData> {
+        self.purge_expired(now).await?;
+        let rows = sqlx::query("SELECT id, saved_at FROM packets")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|r| (r.get("id"), r.get::<i64, _>("saved_at") as u64))
+            .collect())
+    }
+}
+
+trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that runs alongside the system clock with an adjustable offset,
+/// or stands still while frozen.
+#[derive(Debug, Default)]
+struct TestClock {
+    state: Mutex<TestClockState>,
+}
+
+#[derive(Debug, Default)]
+struct TestClockState {
+    offset: chrono::Duration,
+    frozen_at: Option<DateTime<Utc>>,
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        let state = self.state.lock().unwrap();
+        state.frozen_at.unwrap_or_else(|| Utc::now() + state.offset)
+    }
+}
+
+/// Packet timestamps are unsigned seconds, so the clock can't be moved
+/// before the Unix epoch.
+fn check_settable(to: DateTime<Utc>) -> Result<(), String> {
+    if to.timestamp() < 0 {
+        return Err(format!("{} is before the Unix epoch", to));
+    }
+    Ok(())
+}
+
+impl TestClock {
+    fn advance(&self, by: chrono::Duration) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let out_of_range = || "Advancing that far puts the clock out of range".to_string();
+        let now = state.frozen_at.unwrap_or_else(|| Utc::now() + state.offset);
+        check_settable(now.checked_add_signed(by).ok_or_else(out_of_range)?)?;
+        match state.frozen_at.as_mut() {
+            Some(frozen_at) => *frozen_at = now.checked_add_signed(by).ok_or_else(out_of_range)?,
+            None => state.offset = state.offset.checked_add(&by).ok_or_else(out_of_range)?,
+        }
+        Ok(())
+    }
+
+    fn set(&self, to: DateTime<Utc>) -> Result<(), String> {
+        check_settable(to)?;
+        let mut state = self.state.lock().unwrap();
+        match state.frozen_at.as_mut() {
+            Some(frozen_at) => *frozen_at = to,
+            None => state.offset = to - Utc::now(),
+        }
+        Ok(())
+    }
+
+    fn freeze(&self, frozen: bool) {
+        let now = self.now();
+        let mut state = self.state.lock().unwrap();
+        if frozen {
+            state.frozen_at = Some(now);
+        } else if let Some(frozen_at) = state.frozen_at.take() {
+            state.offset = frozen_at - Utc::now();
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ClockAdjustment {
+    advance: Option<i64>,
+    set: Option<DateTime<Utc>>,
+    freeze: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClockStatus {
+    now: DateTime<Utc>,
+    frozen: bool,
+}
+
 struct AppState {
-    ctx: Mutex<AppData>,
+    store: Arc<dyn PacketStore>,
+    clock: Arc<dyn Clock>,
+    generator: Mutex<Generator>,
+}
+
+impl AppState {
+    /// The test clock refuses to go before the epoch; a system clock that
+    /// does is pinned to it rather than wrapping around.
+    fn now(&self) -> u64 {
+        u64::try_from(self.clock.now().timestamp()).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PacketAge {
+    id: String,
+    age: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -29,81 +269,499 @@ struct TimeClassifier {
     lsb_one: usize,
 }
 
-pub fn router() -> Router {
+/// Packets are kept in memory unless `DAY12_STORE=postgres`; their lifetime
+/// comes from `DAY12_PACKET_TTL_SECS`. Setting `DAY12_TEST_CLOCK=1` swaps the
+/// system clock for one driven through `/12/admin/clock`.
+pub fn router(pool: PgPool) -> Router {
+    let ttl = std::env::var("DAY12_PACKET_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_PACKET_TTL_SECS);
+    let store: Arc<dyn PacketStore> = match std::env::var("DAY12_STORE").as_deref() {
+        Ok("postgres") => Arc::new(PgPacketStore::new(pool, ttl)),
+        _ => Arc::new(MemoryPacketStore::new(ttl)),
+    };
+    let test_clock = std::env::var("DAY12_TEST_CLOCK")
+        .is_ok_and(|v| v == "1" || v == "true")
+        .then(|| Arc::new(TestClock::default()));
+    let clock: Arc<dyn Clock> = match &test_clock {
+        Some(test_clock) => test_clock.clone(),
+        None => Arc::new(SystemClock),
+    };
     let shared_state = Arc::new(AppState {
-        ctx: Mutex::new(HashMap::new()),
+        store,
+        clock,
+        generator: Mutex::new(Generator::new()),
     });
+
+    let admin = match test_clock {
+        Some(test_clock) => Router::new()
+            .route("/12/admin/clock", get(clock_status).post(adjust_clock))
+            .with_state(test_clock),
+        None => Router::new(),
+    };
     Router::new()
         .route("/12/save/:pkg_id", post(set_time))
         .route("/12/load/:pkg_id", get(get_time))
+        .route("/12/packets", get(list_packets))
         .route("/12/ulids", post(convert_ulids_to_uuids))
+        .route("/12/uuids", post(convert_uuids_to_ulids))
+        .route("/12/ulid/new", get(generate_ulids))
+        .route("/12/ulid/:id/inspect", get(inspect_ulid))
         .route("/12/ulids/:weekday", post(organize_ulids_by_weekday))
         .with_state(shared_state)
+        .merge(admin)
+}
+
+async fn clock_status(State(clock): State<Arc<TestClock>>) -> Json<ClockStatus> {
+    Json(ClockStatus {
+        now: clock.now(),
+        frozen: clock.state.lock().unwrap().frozen_at.is_some(),
+    })
+}
+
+/// Applies `freeze`, then `set`, then `advance` (in seconds), so a single
+/// call can pin the clock to a known instant. The clock can't be moved out
+/// of chrono's range or before the Unix epoch.
+async fn adjust_clock(
+    State(clock): State<Arc<TestClock>>,
+    Json(adjustment): Json<ClockAdjustment>,
+) -> Result<Json<ClockStatus>, (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let advance = adjustment
+        .advance
+        .map(|seconds| {
+            chrono::Duration::try_seconds(seconds)
+                .ok_or_else(|| format!("Cannot advance by {} seconds", seconds))
+        })
+        .transpose()
+        .map_err(bad_request)?;
+    if let Some(to) = adjustment.set {
+        check_settable(to).map_err(bad_request)?;
+    }
+
+    if let Some(frozen) = adjustment.freeze {
+        clock.freeze(frozen);
+    }
+    if let Some(to) = adjustment.set {
+        clock.set(to).map_err(bad_request)?;
+    }
+    if let Some(by) = advance {
+        clock.advance(by).map_err(bad_request)?;
+    }
+    Ok(clock_status(State(clock)).await)
 }
 
 async fn set_time(
     Path(pkg_id): Path<String>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let current_time = Utc::now();
-    state
-        .ctx
-        .lock()
-        .unwrap()
-        .insert(pkg_id, current_time.timestamp() as u64);
-    let response = state.ctx.lock().unwrap().clone();
-    Json(response).into_response()
+) -> Result<Json<AppData>, AppError> {
+    let current_time = state.now();
+    state.store.save(&pkg_id, current_time).await?;
+    Ok(Json(state.store.list(current_time).await?))
 }
 
 async fn get_time(
     Path(pkg_id): Path<String>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let current_time = Utc::now();
-    let time_diff =
-        current_time.timestamp() as u64 - state.ctx.lock().unwrap().get(&pkg_id).unwrap().clone(); // - current_time.timestamp() as u64;
-
-    time_diff.to_string().into_response()
-}
-
-async fn convert_ulids_to_uuids(Json(ulids): Json<Vec<String>>) -> impl IntoResponse {
-    let ulids_converted = ulids
-        .iter()
-        .map(|x| Ulid::from_string(x))
-        .map(|r| r.unwrap())
-        .map(|u| u.to_bytes())
-        .map(|b| Uuid::from_bytes(b))
-        .map(|u| format!("{}", u.hyphenated()))
-        .rev()
+) -> Result<Response, AppError> {
+    let current_time = state.now();
+    match state.store.load(&pkg_id, current_time).await? {
+        Some(saved) => Ok(current_time
+            .saturating_sub(saved)
+            .to_string()
+            .into_response()),
+        None => Ok((StatusCode::NOT_FOUND, format!("Unknown packet {}", pkg_id)).into_response()),
+    }
+}
+
+async fn list_packets(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<PacketAge>>, AppError> {
+    let current_time = state.now();
+    let mut packets = state
+        .store
+        .list(current_time)
+        .await?
+        .into_iter()
+        .map(|(id, saved)| PacketAge {
+            id,
+            age: current_time.saturating_sub(saved),
+        })
         .collect::<Vec<_>>();
-    Json(ulids_converted).into_response()
+    packets.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(Json(packets))
 }
 
-async fn organize_ulids_by_weekday(
-    Path(weekday): Path<String>,
+#[derive(Debug, Deserialize, Default)]
+struct ConversionQuery {
+    keep_order: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConversionError {
+    index: usize,
+    input: String,
+    error: String,
+}
+
+/// Converts every entry, reversing the output unless `keep_order` is set.
+/// Any invalid entries fail the request with one error per entry.
+fn convert_all<F>(inputs: &[String], query: &ConversionQuery, convert: F) -> Response
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    let mut converted = Vec::with_capacity(inputs.len());
+    let mut errors = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        match convert(input) {
+            Ok(output) => converted.push(output),
+            Err(error) => errors.push(ConversionError {
+                index,
+                input: input.clone(),
+                error,
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "errors": errors }))).into_response();
+    }
+    if !query.keep_order.unwrap_or(false) {
+        converted.reverse();
+    }
+    Json(converted).into_response()
+}
+
+async fn convert_ulids_to_uuids(
+    Query(query): Query<ConversionQuery>,
     Json(ulids): Json<Vec<String>>,
-) -> impl IntoResponse {
-    let days = ulids
-        .iter()
-        .map(|x| Ulid::from_string(x))
-        .map(|r| r.unwrap())
-        .fold(TimeClassifier::default(), |mut acc, u| {
-            let millis = u.datetime();
-            let dt: DateTime<Utc> = millis.into();
-            if (dt.weekday().number_from_monday() - 1) as usize == weekday.parse::<usize>().unwrap()
+) -> Response {
+    convert_all(&ulids, &query, |x| {
+        Ulid::from_string(x)
+            .map(|u| Uuid::from(u).hyphenated().to_string())
+            .map_err(|e| e.to_string())
+    })
+}
+
+async fn convert_uuids_to_ulids(
+    Query(query): Query<ConversionQuery>,
+    Json(uuids): Json<Vec<String>>,
+) -> Response {
+    convert_all(&uuids, &query, |x| {
+        Uuid::parse_str(x)
+            .map(|u| Ulid::from(u).to_string())
+            .map_err(|e| e.to_string())
+    })
+}
+
+const MAX_GENERATED_ULIDS: usize = 1000;
+
+#[derive(Debug, Deserialize, Default)]
+struct GenerateQuery {
+    count: Option<usize>,
+    monotonic: Option<bool>,
+}
+
+/// Monotonic ULIDs share one generator, so they keep increasing across
+/// requests within the same millisecond.
+async fn generate_ulids(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GenerateQuery>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let count = query.count.unwrap_or(1);
+    if count > MAX_GENERATED_ULIDS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("count cannot exceed {}", MAX_GENERATED_ULIDS),
+        ));
+    }
+
+    let now = SystemTime::from(state.clock.now());
+    let ulids = if query.monotonic.unwrap_or(false) {
+        let mut generator = state.generator.lock().unwrap();
+        (0..count)
+            .map(|_| generator.generate_from_datetime(now))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?
+    } else {
+        (0..count).map(|_| Ulid::from_datetime(now)).collect()
+    };
+    Ok(Json(ulids.iter().map(Ulid::to_string).collect()))
+}
+
+#[derive(Debug, Serialize)]
+struct UlidInspection {
+    ulid: String,
+    timestamp_ms: u64,
+    datetime: DateTime<Utc>,
+    randomness: String,
+    uuid: String,
+}
+
+async fn inspect_ulid(
+    Path(id): Path<String>,
+) -> Result<Json<UlidInspection>, (StatusCode, String)> {
+    let ulid = Ulid::from_string(&id).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid ULID {}: {}", id, e),
+        )
+    })?;
+    Ok(Json(UlidInspection {
+        ulid: ulid.to_string(),
+        timestamp_ms: ulid.timestamp_ms(),
+        datetime: ulid.datetime().into(),
+        randomness: format!("{:020x}", ulid.random()),
+        uuid: Uuid::from(ulid).hyphenated().to_string(),
+    }))
+}
+
+/// A client-supplied date test, evaluated on the ULID's local date: either
+/// a yearly `{"month", "day"}` like a holiday, or an inclusive
+/// `{"from", "to"}` range of dates.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DatePredicate {
+    Annual { month: u32, day: u32 },
+    Range { from: NaiveDate, to: NaiveDate },
+}
+
+impl DatePredicate {
+    /// Annual dates have to exist in some year, so Feb 29 is fine but
+    /// month 13 or day 40 isn't.
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Self::Annual { month, day }
+                if NaiveDate::from_ymd_opt(2000, *month, *day).is_none() =>
             {
-                acc.weekday += 1;
-            }
-            if dt > Utc::now() {
-                acc.in_the_future += 1;
-            }
-            if dt.day() == 24 && dt.month() == 12 {
-                acc.christmas_eve += 1;
+                Err(format!("month {} day {} is not a date", month, day))
             }
-            if u.0 & 0b1u128 == 1 {
-                acc.lsb_one += 1;
+            _ => Ok(()),
+        }
+    }
+
+    fn matches(&self, date: NaiveDate) -> bool {
+        match self {
+            Self::Annual { month, day } => date.month() == *month && date.day() == *day,
+            Self::Range { from, to } => *from <= date && date <= *to,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WeekdayPayload {
+    Ulids(Vec<String>),
+    WithPredicates {
+        ulids: Vec<String>,
+        #[serde(default)]
+        predicates: BTreeMap<String, DatePredicate>,
+    },
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct WeekdayQuery {
+    tz: Option<String>,
+}
+
+#[derive(Debug, Serialize, Default)]
+struct DateHistogram {
+    weekday: BTreeMap<String, usize>,
+    month: BTreeMap<String, usize>,
+    year: BTreeMap<i32, usize>,
+}
+
+#[derive(Debug, Serialize, Default)]
+struct WeekdayReport {
+    #[serde(flatten)]
+    classifier: TimeClassifier,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    predicates: BTreeMap<String, usize>,
+    histogram: DateHistogram,
+}
+
+/// `:weekday` is either 0 (Monday) to 6 or a weekday name.
+fn parse_weekday(input: &str) -> Option<Weekday> {
+    match input.parse::<u8>() {
+        Ok(n) => Weekday::try_from(n).ok(),
+        Err(_) => input.parse::<Weekday>().ok(),
+    }
+}
+
+/// Classifies ULIDs in the `tz` timezone (UTC by default), along with a
+/// histogram of their local dates.
+async fn organize_ulids_by_weekday(
+    Path(weekday): Path<String>,
+    Query(query): Query<WeekdayQuery>,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<WeekdayPayload>,
+) -> Response {
+    let Some(weekday) = parse_weekday(&weekday) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid weekday {}, expected 0-6 or a name", weekday),
+        )
+            .into_response();
+    };
+    let tz = match query.tz.as_deref().map(str::parse::<Tz>).transpose() {
+        Ok(tz) => tz.unwrap_or(Tz::UTC),
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid tz: {}", e)).into_response(),
+    };
+    let (ulids, predicates) = match payload {
+        WeekdayPayload::Ulids(ulids) => (ulids, BTreeMap::new()),
+        WeekdayPayload::WithPredicates { ulids, predicates } => (ulids, predicates),
+    };
+    for (name, predicate) in &predicates {
+        if let Err(e) = predicate.check() {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid predicate {}: {}", name, e),
+            )
+                .into_response();
+        }
+    }
+
+    let mut parsed = Vec::with_capacity(ulids.len());
+    let mut errors = Vec::new();
+    for (index, input) in ulids.iter().enumerate() {
+        match Ulid::from_string(input) {
+            Ok(ulid) => parsed.push(ulid),
+            Err(e) => errors.push(ConversionError {
+                index,
+                input: input.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "errors": errors }))).into_response();
+    }
+
+    let now = state.clock.now();
+    let mut report = WeekdayReport {
+        predicates: predicates.keys().map(|name| (name.clone(), 0)).collect(),
+        ..Default::default()
+    };
+    for u in parsed {
+        let dt: DateTime<Utc> = u.datetime().into();
+        let local = dt.with_timezone(&tz).date_naive();
+        let acc = &mut report.classifier;
+        if local.weekday() == weekday {
+            acc.weekday += 1;
+        }
+        if dt > now {
+            acc.in_the_future += 1;
+        }
+        if local.day() == 24 && local.month() == 12 {
+            acc.christmas_eve += 1;
+        }
+        if u.0 & 0b1u128 == 1 {
+            acc.lsb_one += 1;
+        }
+        for (name, predicate) in &predicates {
+            if predicate.matches(local) {
+                *report.predicates.entry(name.clone()).or_default() += 1;
             }
-            acc
+        }
+        let histogram = &mut report.histogram;
+        *histogram
+            .weekday
+            .entry(local.weekday().to_string())
+            .or_default() += 1;
+        *histogram
+            .month
+            .entry(format!("{:02}", local.month()))
+            .or_default() += 1;
+        *histogram.year.entry(local.year()).or_default() += 1;
+    }
+    Json(report).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn frozen_state(ttl: u64) -> (Arc<AppState>, Arc<TestClock>) {
+        let clock = Arc::new(TestClock::default());
+        clock.freeze(true);
+        clock.set("2023-12-12T00:00:00Z".parse().unwrap()).unwrap();
+        let state = Arc::new(AppState {
+            store: Arc::new(MemoryPacketStore::new(ttl)),
+            clock: clock.clone(),
+            generator: Mutex::new(Generator::new()),
+        });
+        (state, clock)
+    }
+
+    async fn save(state: &Arc<AppState>, pkg_id: &str) {
+        let Json(_saved) = set_time(Path(pkg_id.to_string()), State(state.clone()))
+            .await
+            .unwrap();
+    }
+
+    /// The packet's age as `/12/load` reports it, or the status it fails with.
+    async fn age(state: &Arc<AppState>, pkg_id: &str) -> Result<u64, StatusCode> {
+        let response = get_time(Path(pkg_id.to_string()), State(state.clone()))
+            .await
+            .unwrap();
+        if response.status() != StatusCode::OK {
+            return Err(response.status());
+        }
+        let Json(packets) = list_packets(State(state.clone())).await.unwrap();
+        let packet = packets.iter().find(|packet| packet.id == pkg_id).unwrap();
+        Ok(packet.age)
+    }
+
+    #[test]
+    fn packets_expire_once_the_test_clock_passes_their_ttl() {
+        block_on(async {
+            let (state, clock) = frozen_state(10);
+            save(&state, "old").await;
+            clock.advance(chrono::Duration::seconds(5)).unwrap();
+            save(&state, "new").await;
+
+            clock.advance(chrono::Duration::seconds(5)).unwrap();
+            assert_eq!(age(&state, "old").await, Ok(10));
+            assert_eq!(age(&state, "new").await, Ok(5));
+
+            clock.advance(chrono::Duration::seconds(1)).unwrap();
+            assert_eq!(age(&state, "old").await, Err(StatusCode::NOT_FOUND));
+            let Json(packets) = list_packets(State(state.clone())).await.unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_eq!((packets[0].id.as_str(), packets[0].age), ("new", 6));
+
+            // Expired packets are purged, so winding back doesn't revive them.
+            clock.advance(chrono::Duration::seconds(-1)).unwrap();
+            assert_eq!(age(&state, "old").await, Err(StatusCode::NOT_FOUND));
         });
-    Json(days).into_response()
+    }
+
+    #[test]
+    fn frozen_test_clock_stands_still() {
+        block_on(async {
+            let (state, _clock) = frozen_state(10);
+            save(&state, "packet").await;
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+            assert_eq!(age(&state, "packet").await, Ok(0));
+        });
+    }
+
+    #[test]
+    fn test_clock_stays_in_range() {
+        let (state, clock) = frozen_state(10);
+        assert!(clock.advance(chrono::Duration::days(-20000)).is_err());
+        assert!(clock.set("1969-12-31T23:59:59Z".parse().unwrap()).is_err());
+        assert!(clock
+            .advance(chrono::Duration::days(1_000_000_000))
+            .is_err());
+        assert_eq!(state.now(), 1702339200);
+    }
 }
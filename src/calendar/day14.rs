@@ -1,11 +1,501 @@
-use axum::{response::IntoResponse, routing::post, Json, Router};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, OnceLock, RwLock},
+};
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::SyntaxSet,
+};
 use tera::Tera;
+use tracing::warn;
+use uuid::Uuid;
+
+const DEFAULT_TEMPLATES_DIR: &str = "templates";
+const LAYOUT_TEMPLATE: &str = "day14.html";
+const LAYOUT_SOURCE: &str = include_str!("../../templates/day14.html");
+
+/// Tags and attributes kept when sanitising rich HTML; everything else is
+/// stripped (the contents of `script` and `style` are dropped entirely).
+const ALLOWED_TAGS: [&str; 21] = [
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "code",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "u",
+    "ul",
+];
+const ALLOWED_GENERIC_ATTRIBUTES: [&str; 1] = ["title"];
+const ALLOWED_LINK_ATTRIBUTES: [&str; 1] = ["href"];
+const ALLOWED_URL_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+const HIGHLIGHT_THEME: &str = "InspiredGitHub";
+
+/// For text between tags.
+fn escape_text(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// For attribute values, quoted or not: anything but alphanumerics and
+/// `,.-_` becomes a numeric character reference.
+fn escape_attribute(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, ',' | '.' | '-' | '_') || !c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("&#x{:02X};", c as u32));
+        }
+    }
+    escaped
+}
+
+/// For `href`/`src` values: only relative URLs and allow-listed schemes get
+/// through (others become `#`), then unsafe bytes are percent-encoded and
+/// the result is attribute-escaped.
+fn escape_url(input: &str) -> String {
+    let trimmed = input.trim();
+    let scheme = trimmed
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    let allowed = scheme.map_or(true, |scheme| {
+        ALLOWED_URL_SCHEMES
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(scheme))
+    });
+    if !allowed {
+        return "#".to_string();
+    }
+
+    let mut encoded = String::with_capacity(trimmed.len());
+    for byte in trimmed.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&()*+,;=%".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded.replace('&', "&amp;")
+}
+
+/// For values inside a quoted JavaScript string literal.
+fn escape_js_string(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if c.is_ascii_alphanumeric() || c == ' ' {
+            escaped.push(c);
+        } else if (c as u32) < 0x100 {
+            escaped.push_str(&format!("\\x{:02X}", c as u32));
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                escaped.push_str(&format!("\\u{:04X}", unit));
+            }
+        }
+    }
+    escaped
+}
+
+fn sanitizer<'a>() -> ammonia::Builder<'a> {
+    let mut tag_attributes = HashMap::new();
+    tag_attributes.insert("a", HashSet::from(ALLOWED_LINK_ATTRIBUTES));
+    let mut builder = ammonia::Builder::empty();
+    builder
+        .tags(HashSet::from(ALLOWED_TAGS))
+        .clean_content_tags(HashSet::from(["script", "style"]))
+        .generic_attributes(HashSet::from(ALLOWED_GENERIC_ATTRIBUTES))
+        .tag_attributes(tag_attributes)
+        .url_schemes(HashSet::from(ALLOWED_URL_SCHEMES))
+        .link_rel(Some("noopener noreferrer"));
+    builder
+}
+
+fn sanitize_html(input: &str) -> String {
+    sanitizer().clean(input).to_string()
+}
+
+/// The rich-text allow-list plus what CommonMark tables, footnotes,
+/// strikethrough, images and heading anchors produce.
+fn markdown_sanitizer<'a>() -> ammonia::Builder<'a> {
+    let mut builder = sanitizer();
+    builder
+        .add_tags([
+            "table", "thead", "tbody", "tr", "th", "td", "del", "sup", "div", "img",
+        ])
+        .add_tag_attributes("img", ["src", "alt"])
+        .add_tag_attributes("div", ["id"])
+        .add_allowed_classes("sup", ["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", ["footnote-definition"]);
+    for heading in ["h1", "h2", "h3", "h4", "h5", "h6"] {
+        builder.add_tag_attributes(heading, ["id"]);
+    }
+    for cell in ["th", "td"] {
+        builder.add_tag_attribute_values(
+            cell,
+            "style",
+            [
+                "text-align: left",
+                "text-align: center",
+                "text-align: right",
+            ],
+        );
+    }
+    builder
+}
+
+/// Syntax definitions and theme for code blocks, loaded on first use.
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    fn load() -> Self {
+        let mut themes = ThemeSet::load_defaults();
+        Self {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes
+                .themes
+                .remove(HIGHLIGHT_THEME)
+                .expect("default theme set includes the highlight theme"),
+        }
+    }
+
+    fn highlight(&self, code: &str, language: &str) -> String {
+        let syntax = self
+            .syntaxes
+            .find_syntax_by_token(language)
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+        highlighted_html_for_string(code, &self.syntaxes, syntax, &self.theme)
+            .unwrap_or_else(|_| format!("<pre><code>{}</code></pre>", escape_text(code)))
+    }
+}
+
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if (c.is_whitespace() || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+/// Nested lists of links to the headings, relative to the first heading's
+/// level; shallower headings later on are kept at the top level, and a
+/// skipped level nests only one list deeper.
+fn table_of_contents(headings: &[(u32, String, String)]) -> String {
+    let base = headings.first().map_or(1, |(level, ..)| *level);
+    let mut toc = String::from("<nav><ul>");
+    let mut depth = 1;
+    for (i, (level, slug, text)) in headings.iter().enumerate() {
+        let level = (level + 1).saturating_sub(base).clamp(1, depth + 1);
+        if level > depth {
+            // A deeper heading opens a list inside the current item.
+            toc.push_str("<ul>");
+            depth += 1;
+        } else {
+            if i > 0 {
+                toc.push_str("</li>");
+            }
+            while depth > level {
+                toc.push_str("</ul></li>");
+                depth -= 1;
+            }
+        }
+        toc.push_str(&format!(
+            "<li><a href=\"#{}\">{}</a>",
+            escape_attribute(slug),
+            escape_text(text)
+        ));
+    }
+    if !headings.is_empty() {
+        toc.push_str("</li>");
+    }
+    while depth > 1 {
+        toc.push_str("</ul></li>");
+        depth -= 1;
+    }
+    toc.push_str("</ul></nav>\n");
+    toc
+}
+
+/// Renders CommonMark to sanitised HTML. Highlighted code blocks are swapped
+/// for unguessable placeholders before sanitising and put back afterwards,
+/// so only the highlighter's own markup bypasses the allow-list.
+fn render_markdown(content: &str, highlighter: Option<&Highlighter>, with_toc: bool) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let nonce = Uuid::new_v4().simple().to_string();
+    // The trailing marker keeps block 1's placeholder from matching inside
+    // block 10's.
+    let placeholder = |i: usize| format!("md-code-{}-{}-end", nonce, i);
+
+    let mut events = Vec::new();
+    let mut code_blocks = Vec::new();
+    let mut code: Option<(String, String)> = None;
+    let mut headings = Vec::new();
+    let mut heading: Option<(usize, String)> = None;
+
+    for event in Parser::new_ext(content, options) {
+        if let Some((language, buffer)) = code.as_mut() {
+            match event {
+                Event::Text(text) => buffer.push_str(&text),
+                Event::End(Tag::CodeBlock(_)) => {
+                    let highlighter = highlighter.expect("only collecting code when highlighting");
+                    code_blocks.push(highlighter.highlight(buffer, language));
+                    events.push(Event::Html(
+                        format!("\n{}\n", placeholder(code_blocks.len() - 1)).into(),
+                    ));
+                    code = None;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) if highlighter.is_some() => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((language, String::new()));
+            }
+            Event::Start(Tag::Heading(..)) if with_toc => {
+                heading = Some((events.len(), String::new()));
+                events.push(event);
+            }
+            Event::Text(ref text) | Event::Code(ref text) if heading.is_some() => {
+                if let Some((_, title)) = heading.as_mut() {
+                    title.push_str(text);
+                }
+                events.push(event);
+            }
+            Event::End(Tag::Heading(level, ..)) if with_toc => {
+                let (start, title) = heading.take().unwrap_or_default();
+                let base = slugify(&title);
+                let base = if base.is_empty() {
+                    "section".to_string()
+                } else {
+                    base
+                };
+                let mut slug = base.clone();
+                let mut suffix = 1;
+                while headings.iter().any(|(_, s, _)| *s == slug) {
+                    suffix += 1;
+                    slug = format!("{}-{}", base, suffix);
+                }
+                let level = level as u32;
+                events[start] = Event::Html(format!("<h{} id=\"{}\">", level, slug).into());
+                events.push(Event::Html(format!("</h{}>\n", level).into()));
+                headings.push((level, slug, title));
+            }
+            event => events.push(event),
+        }
+    }
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events.into_iter());
+    let mut sanitized = markdown_sanitizer().clean(&rendered).to_string();
+    for (i, block) in code_blocks.iter().enumerate() {
+        sanitized = sanitized.replace(&placeholder(i), block);
+    }
+
+    if with_toc && !headings.is_empty() {
+        table_of_contents(&headings) + &sanitized
+    } else {
+        sanitized
+    }
+}
+
+/// Exposes an escaper as a Tera filter whose output isn't escaped again.
+struct EscapeFilter(fn(&str) -> String);
+
+impl tera::Filter for EscapeFilter {
+    fn filter(&self, value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+        let input = match value {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        Ok(Value::String((self.0)(&input)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+fn register_escapers(tera: &mut Tera) {
+    tera.register_filter("escape_text", EscapeFilter(escape_text));
+    tera.register_filter("escape_attr", EscapeFilter(escape_attribute));
+    tera.register_filter("escape_url", EscapeFilter(escape_url));
+    tera.register_filter("escape_js", EscapeFilter(escape_js_string));
+    tera.register_filter("sanitize_html", EscapeFilter(sanitize_html));
+}
+
+/// Templates loaded from `TEMPLATES_DIR` (default `templates/`) at startup.
+/// With `DAY14_DEV=1` the directory is re-read before every render, as long
+/// as it could be loaded in the first place. Templates
+/// can escape per context with the `escape_text`, `escape_attr`,
+/// `escape_url`, `escape_js` and `sanitize_html` filters.
+struct TemplateRegistry {
+    tera: RwLock<Tera>,
+    /// Dev mode with a directory to reload from; without one Tera refuses
+    /// to reload at all.
+    reload: bool,
+    highlighter: OnceLock<Highlighter>,
+}
+
+impl TemplateRegistry {
+    fn load() -> Self {
+        let dir = std::env::var("TEMPLATES_DIR").unwrap_or_else(|_| DEFAULT_TEMPLATES_DIR.into());
+        let (mut tera, loaded) = match Tera::new(&format!("{}/**/*", dir)) {
+            Ok(tera) => (tera, true),
+            Err(e) => {
+                warn!("Failed to load templates from {}: {:?}", dir, e);
+                (Tera::default(), false)
+            }
+        };
+        let dev = std::env::var("DAY14_DEV").is_ok_and(|v| v == "1" || v == "true");
+        if dev && !loaded {
+            warn!("Not reloading templates, {} could not be loaded", dir);
+        }
+        // Extended templates survive `full_reload`, and files in the
+        // directory take precedence over them.
+        let mut builtins = Tera::default();
+        builtins
+            .add_raw_template(LAYOUT_TEMPLATE, LAYOUT_SOURCE)
+            .expect("built-in layout template is valid");
+        tera.extend(&builtins)
+            .expect("built-in templates can be merged");
+        register_escapers(&mut tera);
+
+        Self {
+            tera: RwLock::new(tera),
+            reload: dev && loaded,
+            highlighter: OnceLock::new(),
+        }
+    }
+
+    fn render(&self, name: &str, context: &tera::Context) -> Result<String, TemplateError> {
+        if self.reload {
+            if let Err(e) = self.tera.write().unwrap().full_reload() {
+                return Err(TemplateError::new(StatusCode::BAD_REQUEST, "reload", &e));
+            }
+        }
+        let tera = self.tera.read().unwrap();
+        if !tera.get_template_names().any(|n| n == name) {
+            return Err(TemplateError {
+                status: StatusCode::NOT_FOUND,
+                kind: "not_found",
+                message: format!("Template '{}' is not registered", name),
+                causes: Vec::new(),
+            });
+        }
+        tera.render(name, context)
+            .map_err(|e| TemplateError::new(StatusCode::BAD_REQUEST, "render", &e))
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .tera
+            .read()
+            .unwrap()
+            .get_template_names()
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+/// Template failures as a JSON body, with Tera's error chain in `causes`.
+#[derive(Serialize, Debug)]
+struct TemplateError {
+    #[serde(skip)]
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+    causes: Vec<String>,
+}
+
+impl TemplateError {
+    fn new(status: StatusCode, kind: &'static str, error: &tera::Error) -> Self {
+        let mut causes = Vec::new();
+        let mut source = std::error::Error::source(error);
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        Self {
+            status,
+            kind,
+            message: error.to_string(),
+            causes,
+        }
+    }
+}
+
+impl IntoResponse for TemplateError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
 
 pub fn router() -> Router {
+    let registry = Arc::new(TemplateRegistry::load());
     Router::new()
         .route("/14/unsafe", post(unsafe_render))
         .route("/14/safe", post(safe_render))
+        .route("/14/markdown", post(markdown_render))
+        .route("/14/templates", get(list_templates))
+        .route("/14/templates/validate", post(validate_template))
+        .route("/14/render/:template", post(render_template))
+        .with_state(registry)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,53 +506,257 @@ struct UnsafePayload {
 #[derive(Serialize, Deserialize, Debug)]
 struct SafePayload {
     content: String,
+    /// Keep allow-listed formatting instead of escaping everything.
+    #[serde(default)]
+    rich: bool,
 }
 
-async fn unsafe_render(Json(payload): Json<UnsafePayload>) -> impl IntoResponse {
-    let mut tera = Tera::default();
-    tera.add_raw_template(
-        "unsafe_render",
-        r#"<html>
-  <head>
-    <title>CCH23 Day 14</title>
-  </head>
-  <body>
-    {{ content }}
-  </body>
-</html>"#,
-    )
-    .unwrap();
-    let context = tera::Context::from_serialize(payload).unwrap();
-    tera.render("unsafe_render", &context)
-        .unwrap()
-        .into_response()
-}
-
-async fn safe_render(Json(payload): Json<SafePayload>) -> impl IntoResponse {
+fn html(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], body).into_response()
+}
+
+fn context_from(value: &Value) -> Result<tera::Context, TemplateError> {
+    tera::Context::from_value(value.clone())
+        .map_err(|e| TemplateError::new(StatusCode::BAD_REQUEST, "context", &e))
+}
+
+async fn unsafe_render(
+    State(registry): State<Arc<TemplateRegistry>>,
+    Json(payload): Json<UnsafePayload>,
+) -> Result<String, TemplateError> {
+    let context = tera::Context::from_serialize(payload)
+        .map_err(|e| TemplateError::new(StatusCode::BAD_REQUEST, "context", &e))?;
+    registry.render(LAYOUT_TEMPLATE, &context)
+}
+
+async fn safe_render(
+    State(registry): State<Arc<TemplateRegistry>>,
+    Json(payload): Json<SafePayload>,
+) -> Result<String, TemplateError> {
+    let content = if payload.rich {
+        sanitize_html(&payload.content)
+    } else {
+        escape_text(&payload.content)
+    };
+    let mut context = tera::Context::new();
+    context.insert("content", &content);
+    registry.render(LAYOUT_TEMPLATE, &context)
+}
+
+async fn list_templates(State(registry): State<Arc<TemplateRegistry>>) -> Json<Vec<String>> {
+    Json(registry.names())
+}
+
+async fn render_template(
+    State(registry): State<Arc<TemplateRegistry>>,
+    Path(template): Path<String>,
+    Json(context): Json<Value>,
+) -> Result<Response, TemplateError> {
+    let context = context_from(&context)?;
+    registry.render(&template, &context).map(html)
+}
+
+#[derive(Deserialize, Debug)]
+struct ValidationRequest {
+    source: String,
+    context: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct ValidationResult {
+    valid: bool,
+    rendered: Option<String>,
+}
+
+/// Parses a candidate template against the registered ones, so it may
+/// extend or include them, and renders it too when a context is given.
+async fn validate_template(
+    State(registry): State<Arc<TemplateRegistry>>,
+    Json(request): Json<ValidationRequest>,
+) -> Result<Json<ValidationResult>, TemplateError> {
+    const CANDIDATE: &str = "__candidate__";
+
     let mut tera = Tera::default();
-    tera.add_raw_template(
-        "safe_render",
-        r#"<html>
-  <head>
-    <title>CCH23 Day 14</title>
-  </head>
-  <body>
-    {{ content }}
-  </body>
-</html>"#,
-    )
-    .unwrap();
+    tera.extend(&registry.tera.read().unwrap())
+        .map_err(|e| TemplateError::new(StatusCode::INTERNAL_SERVER_ERROR, "registry", &e))?;
+    tera.add_raw_template(CANDIDATE, &request.source)
+        .map_err(|e| TemplateError::new(StatusCode::BAD_REQUEST, "parse", &e))?;
+
+    let rendered = match &request.context {
+        Some(context) => Some(
+            tera.render(CANDIDATE, &context_from(context)?)
+                .map_err(|e| TemplateError::new(StatusCode::BAD_REQUEST, "render", &e))?,
+        ),
+        None => None,
+    };
+    Ok(Json(ValidationResult {
+        valid: true,
+        rendered,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+struct MarkdownPayload {
+    content: String,
+    #[serde(default)]
+    highlight: bool,
+    #[serde(default)]
+    toc: bool,
+}
+
+async fn markdown_render(
+    State(registry): State<Arc<TemplateRegistry>>,
+    Json(payload): Json<MarkdownPayload>,
+) -> Result<Response, TemplateError> {
+    let highlighter = payload
+        .highlight
+        .then(|| registry.highlighter.get_or_init(Highlighter::load));
     let mut context = tera::Context::new();
     context.insert(
         "content",
-        &payload
-            .content
-            .replace('<', "&lt;")
-            .replace('>', "&gt;")
-            .replace('"', "&quot;"),
+        &render_markdown(&payload.content, highlighter, payload.toc),
     );
-    // dbg!(tera.render("safe_render", &context).unwrap());
-    tera.render("safe_render", &context)
-        .unwrap()
-        .into_response()
+    registry.render(LAYOUT_TEMPLATE, &context).map(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_text_covers_markup_and_quotes() {
+        assert_eq!(
+            escape_text(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#x27;Jerry&#x27;&lt;/a&gt;"
+        );
+        assert_eq!(escape_text("&amp;"), "&amp;amp;");
+        assert_eq!(escape_text("plain text ünïcode"), "plain text ünïcode");
+    }
+
+    #[test]
+    fn escape_attribute_leaves_nothing_to_break_out_with() {
+        assert_eq!(
+            escape_attribute(r#"" onmouseover='x' `y`"#),
+            "&#x22;&#x20;onmouseover&#x3D;&#x27;x&#x27;&#x20;&#x60;y&#x60;"
+        );
+        assert_eq!(escape_attribute("a<b>&c"), "a&#x3C;b&#x3E;&#x26;c");
+        assert_eq!(escape_attribute("safe-value_1.0,2"), "safe-value_1.0,2");
+        assert_eq!(escape_attribute("tab\there"), "tab&#x09;here");
+    }
+
+    #[test]
+    fn escape_url_refuses_script_schemes() {
+        for url in [
+            "javascript:alert(1)",
+            "JaVaScRiPt:alert(1)",
+            "  javascript:alert(1)",
+            "java\tscript:alert(1)",
+            "\u{1}javascript:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html;base64,PHNjcmlwdD4=",
+            "javascript&colon;:alert(1)",
+        ] {
+            assert_eq!(escape_url(url), "#", "{:?} should be refused", url);
+        }
+    }
+
+    #[test]
+    fn escape_url_keeps_safe_urls_inert() {
+        assert_eq!(
+            escape_url("https://example.com/a?b=1&c=2#d"),
+            "https://example.com/a?b=1&amp;c=2#d"
+        );
+        assert_eq!(
+            escape_url("MAILTO:elf@example.com"),
+            "MAILTO:elf@example.com"
+        );
+        assert_eq!(
+            escape_url("/relative/path:with-colon"),
+            "/relative/path:with-colon"
+        );
+        // Without a real colon this is a relative URL, and the `&` can't
+        // form an entity once escaped.
+        assert_eq!(
+            escape_url("javascript&#58;alert(1)"),
+            "javascript&amp;#58;alert(1)"
+        );
+        // An entity can't start a scheme, so these are relative URLs too.
+        assert_eq!(
+            escape_url("&#106;avascript:alert(1)"),
+            "&amp;#106;avascript:alert(1)"
+        );
+        assert_eq!(
+            escape_url("&#x6A;avascript:alert(1)"),
+            "&amp;#x6A;avascript:alert(1)"
+        );
+        assert_eq!(
+            escape_url(r#"https://x/"><script>'`"#),
+            "https://x/%22%3E%3Cscript%3E%27%60"
+        );
+        assert_eq!(escape_url("https://x/a b\n"), "https://x/a%20b");
+    }
+
+    #[test]
+    fn escape_js_string_cannot_close_the_string_or_script() {
+        assert_eq!(escape_js_string(r#"'"`"#), r"\x27\x22\x60");
+        assert_eq!(escape_js_string("</script>"), r"\x3C\x2Fscript\x3E");
+        assert_eq!(escape_js_string("a\\b\nc"), r"a\x5Cb\x0Ac");
+        assert_eq!(escape_js_string("\u{2028}\u{2029}"), r"\u2028\u2029");
+        assert_eq!(escape_js_string("😀"), r"\uD83D\uDE00");
+        assert_eq!(escape_js_string("${x}"), r"\x24\x7Bx\x7D");
+    }
+
+    #[test]
+    fn table_of_contents_nests_one_level_at_a_time() {
+        let heading = |level: u32, name: &str| (level, name.to_string(), name.to_string());
+        assert_eq!(
+            table_of_contents(&[heading(1, "a"), heading(3, "b"), heading(2, "c")]),
+            "<nav><ul><li><a href=\"#a\">a</a><ul>\
+             <li><a href=\"#b\">b</a></li>\
+             <li><a href=\"#c\">c</a></li></ul></li></ul></nav>\n"
+        );
+        assert_eq!(
+            table_of_contents(&[heading(2, "a"), heading(4, "b"), heading(1, "c")]),
+            "<nav><ul><li><a href=\"#a\">a</a><ul>\
+             <li><a href=\"#b\">b</a></li></ul></li>\
+             <li><a href=\"#c\">c</a></li></ul></nav>\n"
+        );
+    }
+
+    #[test]
+    fn highlighted_code_blocks_are_each_restored_in_place() {
+        let highlighter = Highlighter::load();
+        let content: String = (0..12)
+            .map(|i| format!("Block {}:\n\n```\nline {} of code\n```\n\n", i, i))
+            .collect();
+        let html = render_markdown(&content, Some(&highlighter), false);
+        assert!(!html.contains("md-code-"), "placeholder left in {}", html);
+
+        let mut from = 0;
+        for i in 0..12 {
+            let line = format!("line {} of code", i);
+            assert_eq!(html.matches(&line).count(), 1, "{:?} in {}", line, html);
+            let at = html.find(&line).unwrap();
+            assert!(at > from, "{:?} out of order in {}", line, html);
+            assert!(html[from..at].contains(&format!("Block {}:", i)));
+            from = at;
+        }
+    }
+
+    #[test]
+    fn sanitize_html_keeps_only_the_allow_list() {
+        assert_eq!(
+            sanitize_html(r#"<b onclick="x()">bold</b><script>alert(1)</script>"#),
+            "<b>bold</b>"
+        );
+        assert_eq!(
+            sanitize_html(r#"<a href="javascript:alert(1)" title="t">x</a>"#),
+            r#"<a title="t" rel="noopener noreferrer">x</a>"#
+        );
+        assert_eq!(
+            sanitize_html(r#"<a href="https://example.com">x</a>"#),
+            r#"<a href="https://example.com" rel="noopener noreferrer">x</a>"#
+        );
+        assert_eq!(sanitize_html("<img src=x onerror=alert(1)>"), "");
+    }
 }
@@ -1,15 +1,27 @@
 use axum::{
-    extract::rejection::JsonRejection,
+    extract::{rejection::JsonRejection, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Json, Router,
 };
 use regex::Regex;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
-use std::fmt::Write;
+use std::{collections::HashMap, fmt::Write, path::PathBuf, sync::Arc};
+use tracing::warn;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{confusable_detection::skeleton, MixedScript};
+use unicode_segmentation::UnicodeSegmentation;
+
+use cch23_adas::breached::{BreachedCorpus, DEFAULT_BREACHED_FILE};
+
+const DEFAULT_POLICIES_DIR: &str = "policies";
+const BUILTIN_POLICIES: [(&str, &str); 2] = [
+    ("nice.toml", include_str!("../../policies/nice.toml")),
+    ("game.toml", include_str!("../../policies/game.toml")),
+];
 
 #[derive(Deserialize, Debug, Serialize)]
 struct Password {
@@ -22,29 +34,514 @@ struct PasswordValidation {
 }
 
 pub fn router() -> Router {
+    let path =
+        std::env::var("DAY15_BREACHED_FILE").unwrap_or_else(|_| DEFAULT_BREACHED_FILE.into());
+    let breached = Arc::new(BreachedCorpus::new(PathBuf::from(path)));
+    let context = RuleContext {
+        breached: breached.clone(),
+    };
+    let policies = Arc::new(PolicyRegistry::load(&RuleRegistry::builtin(context)));
+
+    let breached_router = Router::new()
+        .route("/15/breached", post(check_breached))
+        .route("/15/breached/range/:prefix", get(breached_range));
+
     Router::new()
         .route("/15/nice", post(validate_password))
         .route("/15/game", post(password_validation_game))
+        .route("/15/check", post(check_password))
+        .route("/15/policies", get(list_policies))
+        .with_state(policies)
+        .merge(breached_router.with_state(breached))
+}
+
+/// A single password check. Plain `fn(&str) -> bool`s are rules, as are
+/// closures built from a policy's `params`.
+trait Rule: Send + Sync {
+    fn check(&self, input: &str) -> bool;
+}
+
+impl<F> Rule for F
+where
+    F: Fn(&str) -> bool + Send + Sync,
+{
+    fn check(&self, input: &str) -> bool {
+        self(input)
+    }
+}
+
+type RuleFactory = fn(&RuleContext, &Value) -> Result<BuiltRule, String>;
+
+/// Shared resources rules may need beyond their own parameters.
+struct RuleContext {
+    breached: Arc<BreachedCorpus>,
+}
+
+/// A rule ready to run, with the hint shown when it fails.
+struct BuiltRule {
+    check: Box<dyn Rule>,
+    suggestion: String,
+}
+
+fn built(check: impl Rule + 'static, suggestion: impl Into<String>) -> Result<BuiltRule, String> {
+    Ok(BuiltRule {
+        check: Box::new(check),
+        suggestion: suggestion.into(),
+    })
+}
+
+/// Maps the rule names used in policy files to constructors.
+struct RuleRegistry {
+    context: RuleContext,
+    factories: HashMap<&'static str, RuleFactory>,
+}
+
+fn param<T: DeserializeOwned>(params: &Value, key: &str, default: T) -> Result<T, String> {
+    match params.get(key) {
+        Some(value) => serde_json::from_value(value.clone())
+            .map_err(|e| format!("invalid parameter '{}': {}", key, e)),
+        None => Ok(default),
+    }
+}
+
+impl RuleRegistry {
+    fn builtin(context: RuleContext) -> Self {
+        let mut factories: HashMap<&'static str, RuleFactory> = HashMap::new();
+        factories.insert("double_letter", |_, _| {
+            built(
+                contains_double_letter,
+                "Put the same letter twice in a row, like \"ee\"",
+            )
+        });
+        factories.insert("vowels", |_, params| {
+            let count: usize = param(params, "count", 3)?;
+            let locale: Option<String> = param(params, "locale", None)?;
+            let vowels: Option<String> = match param::<Option<String>>(params, "vowels", None)? {
+                Some(vowels) => Some(vowels),
+                None => locale
+                    .map(|locale| {
+                        vowels_for(&locale)
+                            .ok_or_else(|| format!("no vowel set for locale '{}'", locale))
+                    })
+                    .transpose()?
+                    .map(String::from),
+            };
+            // Without a locale or vowel set, only lowercase ASCII vowels count.
+            let Some(vowels) = vowels else {
+                return built(
+                    move |input: &str| contains_ascii_vowels(input) >= count,
+                    format!("Use at least {} vowels (aeiou)", count),
+                );
+            };
+            let suggestion = format!("Use at least {} vowels ({})", count, vowels);
+            built(
+                move |input: &str| contains_vowels(input, &vowels) >= count,
+                suggestion,
+            )
+        });
+        factories.insert("forbidden_substrings", |_, params| {
+            let values: Vec<String> = param(
+                params,
+                "values",
+                ["ab", "cd", "pq", "xy"].map(String::from).to_vec(),
+            )?;
+            let suggestion = format!("Remove any of: {}", values.join(", "));
+            built(
+                move |input: &str| !values.iter().any(|v| input.contains(v.as_str())),
+                suggestion,
+            )
+        });
+        factories.insert("min_length", |_, params| {
+            let min: usize = param(params, "min", 8)?;
+            built(
+                move |input: &str| input.graphemes(true).count() >= min,
+                format!("Make it at least {} characters long", min),
+            )
+        });
+        factories.insert("all_char_types", |_, _| {
+            built(
+                contains_all_types_alphanumeric,
+                "Mix uppercase letters, lowercase letters and digits",
+            )
+        });
+        factories.insert("min_digits", |_, params| {
+            let count: usize = param(params, "count", 5)?;
+            built(
+                move |input: &str| {
+                    input
+                        .graphemes(true)
+                        .filter(|g| g.chars().next().is_some_and(char::is_numeric))
+                        .count()
+                        >= count
+                },
+                format!("Include at least {} digits", count),
+            )
+        });
+        factories.insert("digits_sum", |_, params| {
+            let target: i64 = param(params, "target", 2023)?;
+            built(
+                move |input: &str| sequence_arithmetic(input) == Some(target),
+                format!("Make the numbers in it add up to {}", target),
+            )
+        });
+        factories.insert("joyful", |_, _| {
+            built(
+                is_joyful,
+                "Spell out j, o and y in that order, using each only once",
+            )
+        });
+        factories.insert("sandwich", |_, _| {
+            built(
+                has_sandwich,
+                "Put a letter between two copies of another, like \"aba\"",
+            )
+        });
+        factories.insert("char_range", |_, params| {
+            let from: char = param(params, "from", '\u{2980}')?;
+            let to: char = param(params, "to", '\u{2BFF}')?;
+            let re = Regex::new(&format!(
+                "[{}-{}]",
+                regex::escape(&from.to_string()),
+                regex::escape(&to.to_string())
+            ))
+            .map_err(|e| e.to_string())?;
+            built(
+                move |input: &str| re.is_match(input),
+                format!("Include a character between {} and {}", from, to),
+            )
+        });
+        factories.insert("emoji", |_, _| {
+            built(string_contains_at_least_one_emoji, "Add an emoji")
+        });
+        factories.insert("sha256_suffix", |_, params| {
+            let suffix: String = param(params, "suffix", "a".to_string())?;
+            let suggestion = format!(
+                "Tweak it until its SHA-256 hex digest ends with \"{}\"",
+                suffix
+            );
+            built(
+                move |input: &str| sha256_hex(input).ends_with(&suffix),
+                suggestion,
+            )
+        });
+        factories.insert("not_breached", |context, params| {
+            let max_count: u64 = param(params, "max_count", 0)?;
+            let breached = context.breached.clone();
+            built(
+                // A corpus we can't read fails the rule rather than waving
+                // every password through.
+                move |input: &str| match breached.count(input) {
+                    Ok(count) => count <= max_count,
+                    Err(e) => {
+                        warn!("Failed to read breached corpus: {:?}", e);
+                        false
+                    }
+                },
+                "Pick a password that hasn't shown up in a data breach",
+            )
+        });
+        factories.insert("no_confusables", |_, params| {
+            let allow_mixed_script: bool = param(params, "allow_mixed_script", false)?;
+            built(
+                move |input: &str| {
+                    !contains_confusables(input) && (allow_mixed_script || input.is_single_script())
+                },
+                "Avoid characters that look like other letters, such as Cyrillic \"а\" for \"a\"",
+            )
+        });
+        Self { context, factories }
+    }
+
+    fn build(&self, config: &RuleConfig) -> Result<BuiltRule, String> {
+        let factory = self
+            .factories
+            .get(config.rule.as_str())
+            .ok_or_else(|| format!("unknown rule '{}'", config.rule))?;
+        factory(&self.context, &config.params).map_err(|e| format!("rule '{}': {}", config.rule, e))
+    }
+}
+
+/// A policy file, in JSON or TOML: rules run in order and the first
+/// failing one decides the response.
+#[derive(Deserialize, Debug)]
+struct PolicyConfig {
+    name: String,
+    pass_reason: Option<String>,
+    #[serde(default)]
+    normalization: Normalization,
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RuleConfig {
+    rule: String,
+    #[serde(default)]
+    params: Value,
+    status: u16,
+    reason: Option<String>,
+    suggestion: Option<String>,
 }
 
-async fn contains_double_letter(input: &str) -> bool {
-    let mut chars = input.chars();
-    let mut last_char = chars.next().unwrap();
-    for c in chars {
-        if c == last_char && c.is_alphabetic() {
-            return true;
+struct PolicyRule {
+    name: String,
+    rule: Box<dyn Rule>,
+    status: StatusCode,
+    reason: Option<String>,
+    suggestion: String,
+}
+
+/// Applied to the input before any rule sees it.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Normalization {
+    None,
+    #[default]
+    Nfc,
+    Nfkc,
+}
+
+impl Normalization {
+    fn apply(self, input: &str) -> String {
+        match self {
+            Normalization::None => input.to_string(),
+            Normalization::Nfc => input.nfc().collect(),
+            Normalization::Nfkc => input.nfkc().collect(),
         }
-        last_char = c;
     }
-    false
 }
 
-async fn contains_three_vowels(input: &str) -> bool {
-    input.chars().filter(|c| "aeiou".contains(*c)).count() >= 3
+struct Policy {
+    pass_reason: Option<String>,
+    normalization: Normalization,
+    rules: Vec<PolicyRule>,
+}
+
+impl Policy {
+    fn from_config(config: PolicyConfig, rules: &RuleRegistry) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let status = StatusCode::from_u16(rule.status)
+                    .ok()
+                    .filter(StatusCode::is_client_error)
+                    .ok_or_else(|| format!("invalid status {}, expected 4xx", rule.status))?;
+                let built = rules.build(rule)?;
+                Ok(PolicyRule {
+                    name: rule.rule.clone(),
+                    rule: built.check,
+                    status,
+                    reason: rule.reason.clone(),
+                    suggestion: rule.suggestion.clone().unwrap_or(built.suggestion),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            pass_reason: config.pass_reason,
+            normalization: config.normalization,
+            rules,
+        })
+    }
+
+    fn first_failure(&self, input: &str) -> Option<&PolicyRule> {
+        self.rules.iter().find(|rule| !rule.rule.check(input))
+    }
+
+    fn respond(&self, input: &str) -> (StatusCode, String) {
+        let input = self.normalization.apply(input);
+        match self.first_failure(&input) {
+            None => (
+                StatusCode::OK,
+                verdict("nice", self.pass_reason.as_deref()).to_string(),
+            ),
+            Some(rule) => (
+                rule.status,
+                verdict("naughty", rule.reason.as_deref()).to_string(),
+            ),
+        }
+    }
+
+    /// Runs every rule rather than stopping at the first failure.
+    fn report(&self, input: &str) -> (StatusCode, String) {
+        let input = &self.normalization.apply(input);
+        let (passed, failed): (Vec<_>, Vec<_>) =
+            self.rules.iter().partition(|rule| rule.rule.check(input));
+        let first = failed.first();
+        let report = FullReport {
+            result: if first.is_some() { "naughty" } else { "nice" },
+            reason: first.map_or(self.pass_reason.as_deref(), |rule| rule.reason.as_deref()),
+            failed: failed
+                .iter()
+                .map(|rule| FailedRule {
+                    rule: &rule.name,
+                    status: rule.status.as_u16(),
+                    reason: rule.reason.as_deref(),
+                    suggestion: &rule.suggestion,
+                })
+                .collect(),
+            passed: passed.iter().map(|rule| rule.name.as_str()).collect(),
+            strength: Strength::estimate(input),
+        };
+        (
+            first.map_or(StatusCode::OK, |rule| rule.status),
+            serde_json::to_string(&report).expect("report serializes"),
+        )
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct FullReport<'a> {
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    failed: Vec<FailedRule<'a>>,
+    passed: Vec<&'a str>,
+    strength: Strength,
+}
+
+#[derive(Serialize, Debug)]
+struct FailedRule<'a> {
+    rule: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    suggestion: &'a str,
+}
+
+fn verdict(result: &str, reason: Option<&str>) -> Value {
+    match reason {
+        Some(reason) => json!({"result": result, "reason": reason}),
+        None => json!({"result": result}),
+    }
+}
+
+/// The built-in `nice` and `game` policies, plus any `*.json`/`*.toml` in
+/// `POLICIES_DIR` (default `policies/`), which override built-ins by name.
+/// Files load in name order, and a file reusing another file's policy name
+/// is skipped.
+struct PolicyRegistry {
+    policies: HashMap<String, Policy>,
+}
+
+fn parse_policy(file_name: &str, source: &str) -> Result<PolicyConfig, String> {
+    if file_name.ends_with(".json") {
+        serde_json::from_str(source).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(source).map_err(|e| e.to_string())
+    }
+}
+
+impl PolicyRegistry {
+    fn load(rules: &RuleRegistry) -> Self {
+        let mut sources: Vec<(String, String, bool)> = BUILTIN_POLICIES
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string(), true))
+            .collect();
+
+        let dir = std::env::var("POLICIES_DIR").unwrap_or_else(|_| DEFAULT_POLICIES_DIR.into());
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            let mut entries: Vec<_> = entries.flatten().collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if !(file_name.ends_with(".json") || file_name.ends_with(".toml")) {
+                    continue;
+                }
+                match std::fs::read_to_string(entry.path()) {
+                    Ok(source) => sources.push((file_name, source, false)),
+                    Err(e) => warn!("Failed to read policy {}: {:?}", file_name, e),
+                }
+            }
+        }
+
+        let mut policies = HashMap::new();
+        let mut defined_in: HashMap<String, String> = HashMap::new();
+        for (file_name, source, builtin) in sources {
+            let policy = parse_policy(&file_name, &source).and_then(|config| {
+                if let Some(other) = defined_in.get(&config.name) {
+                    return Err(format!("'{}' is already defined in {}", config.name, other));
+                }
+                let name = config.name.clone();
+                Policy::from_config(config, rules).map(|policy| (name, policy))
+            });
+            match policy {
+                Ok((name, policy)) => {
+                    if !builtin {
+                        defined_in.insert(name.clone(), file_name);
+                    }
+                    policies.insert(name, policy);
+                }
+                Err(e) => warn!("Skipping policy {}: {}", file_name, e),
+            }
+        }
+        Self { policies }
+    }
+
+    fn get(&self, name: &str) -> Option<&Policy> {
+        self.policies.get(name)
+    }
+}
+
+// Rules work on grapheme clusters of the normalised input, so "é" counts
+// once whether it arrived precomposed or as "e" plus a combining accent.
+// Empty input is just a password that fails whatever needs content.
+
+fn is_letter(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(char::is_alphabetic)
+}
+
+fn contains_double_letter(input: &str) -> bool {
+    let graphemes = input.graphemes(true).collect::<Vec<_>>();
+    graphemes
+        .windows(2)
+        .any(|pair| pair[0] == pair[1] && is_letter(pair[0]))
+}
+
+/// Vowels per language; accented forms match through their base letter.
+fn vowels_for(locale: &str) -> Option<&'static str> {
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    Some(match language.to_ascii_lowercase().as_str() {
+        "en" | "es" | "it" | "pt" | "ro" => "aeiou",
+        "fr" | "nl" | "pl" | "cs" | "sk" => "aeiouy",
+        "de" => "aeiouäöü",
+        "sv" | "fi" | "et" => "aeiouyåäö",
+        "da" | "no" | "nb" | "nn" => "aeiouyæøå",
+        "is" => "aeiouyáéíóúýæö",
+        "tr" | "az" => "aeıioöuü",
+        "hu" => "aeiouöü",
+        "ru" | "uk" | "be" | "bg" => "аеёиоуыэюяєії",
+        "el" => "αεηιουω",
+        _ => return None,
+    })
+}
+
+fn contains_ascii_vowels(input: &str) -> usize {
+    input.chars().filter(|c| "aeiou".contains(*c)).count()
+}
+
+/// Case-insensitive, and accented vowels count through their base letter.
+fn contains_vowels(input: &str, vowels: &str) -> usize {
+    input
+        .graphemes(true)
+        .filter(|grapheme| {
+            let lower = grapheme.to_lowercase();
+            let composed = lower.nfc().next();
+            let base = lower.nfd().next();
+            [composed, base]
+                .into_iter()
+                .flatten()
+                .any(|c| vowels.contains(c))
+        })
+        .count()
 }
 
-async fn does_not_contain_forbidden_strings(input: &str) -> bool {
-    !input.contains("ab") && !input.contains("cd") && !input.contains("pq") && !input.contains("xy")
+/// Non-ASCII characters whose confusable skeleton is plain ASCII, i.e.
+/// lookalikes such as Cyrillic "о" or fullwidth "Ａ".
+fn contains_confusables(input: &str) -> bool {
+    input.chars().any(|c| {
+        let mut buf = [0; 4];
+        !c.is_ascii() && skeleton(c.encode_utf8(&mut buf)).all(|s| s.is_ascii())
+    })
 }
 
 struct AppError(JsonRejection);
@@ -59,108 +556,108 @@ impl IntoResponse for AppError {
     }
 }
 
-async fn validate_password(
+#[derive(Deserialize, Debug, Default)]
+struct ReportQuery {
+    report: Option<String>,
+}
+
+impl ReportQuery {
+    fn is_full(&self) -> bool {
+        self.report.as_deref() == Some("full")
+    }
+}
+
+/// Rules such as `not_breached` read from disk, so policies run on the
+/// blocking pool.
+async fn evaluate(
+    policies: Arc<PolicyRegistry>,
+    name: String,
+    query: ReportQuery,
     payload: Result<Json<Password>, JsonRejection>,
 ) -> Result<(StatusCode, String), AppError> {
-    match payload {
-        Ok(payload) => {
-            let mut errors = Vec::new();
-            if !contains_double_letter(&payload.input).await {
-                errors.push("Password does not contain a double letter".to_string());
-            }
-            if !contains_three_vowels(&payload.input).await {
-                errors.push("Password does not contain three vowels".to_string());
-            }
-            if !does_not_contain_forbidden_strings(&payload.input).await {
-                errors.push("Password contains forbidden strings".to_string());
-            }
-            if errors.is_empty() {
-                Ok((
-                    StatusCode::OK,
-                    json!({"result": "nice".to_string()}).to_string(),
-                ))
-            } else {
-                Ok((
-                    StatusCode::BAD_REQUEST,
-                    json!({"result": "naughty"}).to_string(),
-                ))
-            }
-        }
-        Err(e) => Err(AppError(e)),
-    }
+    let payload = payload.map_err(AppError)?;
+    let evaluation = tokio::task::spawn_blocking(move || {
+        policies
+            .get(&name)
+            .map(|policy| match query.is_full() {
+                true => policy.report(&payload.input),
+                false => policy.respond(&payload.input),
+            })
+            .unwrap_or_else(|| {
+                (
+                    StatusCode::NOT_FOUND,
+                    json!({"message": format!("Unknown policy {}", name)}).to_string(),
+                )
+            })
+    });
+    Ok(evaluation.await.unwrap_or_else(|e| {
+        warn!("Policy evaluation failed: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"message": "Failed to evaluate the policy"}).to_string(),
+        )
+    }))
 }
 
-async fn is_at_least_eight_chars_long(input: &str) -> bool {
-    input.len() >= 8
+async fn validate_password(
+    State(policies): State<Arc<PolicyRegistry>>,
+    Query(query): Query<ReportQuery>,
+    payload: Result<Json<Password>, JsonRejection>,
+) -> Result<(StatusCode, String), AppError> {
+    evaluate(policies, "nice".to_string(), query, payload).await
 }
 
-async fn contains_all_types_alphanumeric(input: &str) -> bool {
+fn contains_all_types_alphanumeric(input: &str) -> bool {
     input.chars().any(|c| c.is_numeric())
         && input.chars().any(|c| c.is_lowercase())
         && input.chars().any(|c| c.is_uppercase())
 }
 
-async fn contains_at_least_five_digits(input: &str) -> bool {
-    input.chars().filter(|c| c.is_numeric()).count() >= 5
-}
-
-async fn sequence_arithmetic(input: &str) -> bool {
-    Regex::new(r"\d+")
+/// Sums the runs of ASCII digits, or `None` if that overflows.
+fn sequence_arithmetic(input: &str) -> Option<i64> {
+    Regex::new(r"[0-9]+")
         .unwrap()
         .find_iter(input)
-        .map(|m| m.as_str().parse::<i32>().unwrap())
-        .sum::<i32>()
-        == 2023
+        .try_fold(0i64, |sum, m| sum.checked_add(m.as_str().parse().ok()?))
 }
 
-async fn is_joyful(input: &str) -> bool {
+fn is_joyful(input: &str) -> bool {
     let mut extracted = String::new();
-    let joy = "joy".chars();
-    let mut joy_iter = joy.into_iter();
+    let mut joy_iter = ["j", "o", "y"].into_iter();
 
-    let mut current_char = joy_iter.next();
+    let mut current = joy_iter.next();
 
-    for c in input.chars() {
-        if extracted.contains(c) {
+    for g in input.graphemes(true) {
+        if extracted.contains(g) {
             return false;
         }
-        if Some(c) == current_char {
-            extracted.push(c);
-            current_char = joy_iter.next();
+        if Some(g) == current {
+            extracted.push_str(g);
+            current = joy_iter.next();
         }
-        if current_char.is_none() {
-            joy_iter = "joy".chars().into_iter();
-            current_char = joy_iter.next();
+        if current.is_none() {
+            joy_iter = ["j", "o", "y"].into_iter();
+            current = joy_iter.next();
         }
     }
 
     extracted == "joy"
 }
 
-async fn has_sandwich(input: &str) -> bool {
-    let re = Regex::new(r"\p{L}{3}").unwrap();
-    let matches = re.find_iter(input);
-    for m in matches {
-        let chars = m.as_str().chars().collect::<Vec<_>>();
-        if chars[0] == chars[2] {
-            return true;
-        }
-    }
-    false
-}
-
-async fn string_contains_char_in_range(input: &str) -> bool {
-    let re = Regex::new(r"[\u{2980}-\u{2BFF}]").unwrap();
-    re.is_match(input)
+fn has_sandwich(input: &str) -> bool {
+    let graphemes = input.graphemes(true).collect::<Vec<_>>();
+    graphemes
+        .windows(3)
+        .any(|w| w[0] == w[2] && w.iter().all(|g| is_letter(g)))
 }
 
-async fn string_contains_at_least_one_emoji(input: &str) -> bool {
+fn string_contains_at_least_one_emoji(input: &str) -> bool {
     let emoji_pattern = r"\p{Extended_Pictographic}";
     let re = Regex::new(emoji_pattern).unwrap();
     re.is_match(input)
 }
 
-async fn sha256_ends_with_a(input: &str) -> bool {
+fn sha256_hex(input: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(input.as_bytes());
     let result = hasher.finalize();
@@ -169,80 +666,249 @@ async fn sha256_ends_with_a(input: &str) -> bool {
     for byte in result {
         write!(&mut hex_str, "{:02x}", byte).expect("Unable to write to string");
     }
-    hex_str.chars().last().unwrap() == 'a'
+    hex_str
+}
+
+const COMMON_PASSWORDS: [&str; 16] = [
+    "password", "123456", "12345678", "qwerty", "abc123", "111111", "letmein", "iloveyou", "admin",
+    "welcome", "monkey", "dragon", "football", "sunshine", "princess", "trustno1",
+];
+
+/// A rough zxcvbn-style estimate: brute-force entropy over the character
+/// pool, discounted for repeats, runs like `abc`/`321` and common passwords.
+#[derive(Serialize, Debug)]
+struct Strength {
+    entropy_bits: f64,
+    guesses_log10: f64,
+    score: u8,
+    crack_time: String,
+    warnings: Vec<&'static str>,
+}
+
+impl Strength {
+    fn estimate(input: &str) -> Self {
+        let mut warnings = Vec::new();
+        let lowered = input.to_lowercase();
+        let common = COMMON_PASSWORDS
+            .iter()
+            .filter(|word| lowered.contains(*word))
+            .max_by_key(|word| word.len());
+        let remaining = match common {
+            Some(word) => {
+                warnings.push("contains a common password");
+                lowered.replacen(word, "", 1)
+            }
+            None => lowered.clone(),
+        };
+
+        let chars = input.chars().collect::<Vec<_>>();
+        let pool = [
+            (chars.iter().any(|c| c.is_ascii_lowercase()), 26),
+            (chars.iter().any(|c| c.is_ascii_uppercase()), 26),
+            (chars.iter().any(|c| c.is_ascii_digit()), 10),
+            (
+                chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' '),
+                33,
+            ),
+            (chars.iter().any(|c| !c.is_ascii()), 100),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, size)| size)
+        .sum::<u32>()
+        .max(1);
+        let bits_per_char = f64::from(pool).log2();
+
+        let mut bits = common.map_or(0.0, |_| (COMMON_PASSWORDS.len() as f64).log2());
+        let mut previous: Option<(char, i64)> = None;
+        let (mut repeated, mut sequential) = (false, false);
+        for c in remaining.chars() {
+            let step = previous.map(|(p, _)| c as i64 - p as i64);
+            match (previous, step) {
+                (Some(_), Some(0)) => {
+                    repeated = true;
+                    bits += 1.0;
+                }
+                (Some((_, last_step)), Some(step)) if step.abs() == 1 && step == last_step => {
+                    sequential = true;
+                    bits += 1.0;
+                }
+                _ => bits += bits_per_char,
+            }
+            previous = Some((c, step.unwrap_or_default()));
+        }
+        if repeated {
+            warnings.push("repeated characters are easy to guess");
+        }
+        if sequential {
+            warnings.push("sequences like abc or 123 are easy to guess");
+        }
+
+        let guesses_log10 = bits * std::f64::consts::LOG10_2;
+        let score = match guesses_log10 {
+            g if g < 3.0 => 0,
+            g if g < 6.0 => 1,
+            g if g < 8.0 => 2,
+            g if g < 10.0 => 3,
+            _ => 4,
+        };
+        Self {
+            entropy_bits: (bits * 10.0).round() / 10.0,
+            guesses_log10: (guesses_log10 * 10.0).round() / 10.0,
+            score,
+            // Offline attack against a fast hash, ten billion guesses a second.
+            crack_time: display_duration(10f64.powf(guesses_log10 - 10.0)),
+            warnings,
+        }
+    }
+}
+
+fn display_duration(seconds: f64) -> String {
+    const UNITS: [(&str, f64); 5] = [
+        ("year", 31_536_000.0),
+        ("day", 86_400.0),
+        ("hour", 3_600.0),
+        ("minute", 60.0),
+        ("second", 1.0),
+    ];
+    if seconds < 1.0 {
+        return "less than a second".to_string();
+    }
+    if seconds >= 100.0 * UNITS[0].1 {
+        return "centuries".to_string();
+    }
+    let (unit, size) = UNITS
+        .iter()
+        .find(|(_, size)| seconds >= *size)
+        .expect("seconds is at least one");
+    let count = (seconds / size).round() as u64;
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
 }
 
 async fn password_validation_game(
+    State(policies): State<Arc<PolicyRegistry>>,
+    Query(query): Query<ReportQuery>,
     payload: Result<Json<Password>, JsonRejection>,
 ) -> Result<(StatusCode, String), AppError> {
-    match payload {
-        Ok(payload) => {
-            let mut errors = Vec::new();
-            if !is_at_least_eight_chars_long(&payload.input).await {
-                errors.push((
-                    StatusCode::BAD_REQUEST,
-                    json!({"result": "naughty", "reason": "8 chars"}).to_string(),
-                ));
-            }
-            if !contains_all_types_alphanumeric(&payload.input).await {
-                errors.push((
-                    StatusCode::BAD_REQUEST,
-                    json!({"result": "naughty", "reason": "more types of chars"}).to_string(),
-                ));
-            }
-            if !contains_at_least_five_digits(&payload.input).await {
-                errors.push((
-                    StatusCode::BAD_REQUEST,
-                    json!({"result": "naughty", "reason": "55555"}).to_string(),
-                ));
-            }
-            if !sequence_arithmetic(&payload.input).await {
-                errors.push((
-                    StatusCode::BAD_REQUEST,
-                    json!({"result": "naughty", "reason": "math is hard"}).to_string(),
-                ));
-            }
-            if !is_joyful(&payload.input).await {
-                errors.push((
-                    StatusCode::NOT_ACCEPTABLE,
-                    json!({"result": "naughty", "reason": "not joyful enough"}).to_string(),
-                ));
-            }
-            if !has_sandwich(&payload.input).await {
-                errors.push((
-                    StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
-                    json!({"result": "naughty", "reason": "illegal: no sandwich"}).to_string(),
-                ));
-            }
-            if !string_contains_char_in_range(&payload.input).await {
-                errors.push((
-                    StatusCode::RANGE_NOT_SATISFIABLE,
-                    json!({"result": "naughty", "reason": "outranged"}).to_string(),
-                ));
-            }
-            if !string_contains_at_least_one_emoji(&payload.input).await {
-                errors.push((
-                    StatusCode::UPGRADE_REQUIRED,
-                    json!({"result": "naughty", "reason": "😳"}).to_string(),
-                ));
-            }
-            if !sha256_ends_with_a(&payload.input).await {
-                errors.push((
-                    StatusCode::IM_A_TEAPOT,
-                    json!({"result": "naughty", "reason": "not a coffee brewer"}).to_string(),
-                ));
-            }
-            if errors.is_empty() {
-                return Ok((
-                    StatusCode::OK,
-                    json!({"result": "nice".to_string(), "reason": "that's a nice password"})
-                        .to_string(),
-                ));
-            } else {
-                let result = errors.first().unwrap().clone();
-                return Ok(result);
+    evaluate(policies, "game".to_string(), query, payload).await
+}
+
+#[derive(Deserialize, Debug)]
+struct CheckQuery {
+    policy: String,
+    #[serde(flatten)]
+    report: ReportQuery,
+}
+
+async fn check_password(
+    State(policies): State<Arc<PolicyRegistry>>,
+    Query(query): Query<CheckQuery>,
+    payload: Result<Json<Password>, JsonRejection>,
+) -> Result<(StatusCode, String), AppError> {
+    evaluate(policies, query.policy, query.report, payload).await
+}
+
+#[derive(Serialize, Debug)]
+struct PolicySummary {
+    name: String,
+    rules: Vec<String>,
+}
+
+async fn list_policies(State(policies): State<Arc<PolicyRegistry>>) -> Json<Vec<PolicySummary>> {
+    let mut summaries: Vec<PolicySummary> = policies
+        .policies
+        .iter()
+        .map(|(name, policy)| PolicySummary {
+            name: name.clone(),
+            rules: policy.rules.iter().map(|r| r.name.clone()).collect(),
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(summaries)
+}
+
+async fn check_breached(
+    State(breached): State<Arc<BreachedCorpus>>,
+    payload: Result<Json<Password>, JsonRejection>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let payload = payload.map_err(AppError)?;
+    let lookup = tokio::task::spawn_blocking(move || breached.count(&payload.input)).await;
+    Ok(
+        match lookup.map_err(std::io::Error::from).and_then(|count| count) {
+            Ok(count) => (
+                StatusCode::OK,
+                Json(json!({"breached": count > 0, "count": count})),
+            ),
+            Err(e) => {
+                warn!("Failed to read breached corpus: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"message": "Failed to read the breached corpus"})),
+                )
             }
+        },
+    )
+}
+
+async fn breached_range(
+    State(breached): State<Arc<BreachedCorpus>>,
+    Path(prefix): Path<String>,
+) -> (StatusCode, String) {
+    let lookup = tokio::task::spawn_blocking(move || breached.range(&prefix)).await;
+    match lookup.map_err(std::io::Error::from).and_then(|range| range) {
+        Ok(Some(body)) => (StatusCode::OK, body),
+        Ok(None) => (
+            StatusCode::BAD_REQUEST,
+            "The hash prefix must be 5 hex characters".to_string(),
+        ),
+        Err(e) => {
+            warn!("Failed to read breached corpus: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the breached corpus".to_string(),
+            )
         }
-        Err(e) => Err(AppError(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> RuleRegistry {
+        RuleRegistry::builtin(RuleContext {
+            breached: Arc::new(BreachedCorpus::new(PathBuf::from(DEFAULT_BREACHED_FILE))),
+        })
+    }
+
+    fn policy(source: &str) -> Policy {
+        let config = parse_policy("test.toml", source).unwrap();
+        Policy::from_config(config, &rules()).unwrap()
+    }
+
+    #[test]
+    fn nice_counts_only_lowercase_ascii_vowels() {
+        let nice = policy(BUILTIN_POLICIES[0].1);
+        assert_eq!(nice.respond("hello there").0, StatusCode::OK);
+        assert_eq!(nice.respond("hEllO thErE").0, StatusCode::BAD_REQUEST);
+        assert_eq!(nice.respond("AEIOU gg").0, StatusCode::BAD_REQUEST);
+        assert_eq!(nice.respond("éééé gg").0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn vowels_fold_case_and_accents_for_a_locale() {
+        let folding = policy(
+            r#"
+            name = "folding"
+            [[rules]]
+            rule = "vowels"
+            params = { count = 3, locale = "en" }
+            status = 400
+            "#,
+        );
+        assert_eq!(folding.respond("AEI").0, StatusCode::OK);
+        assert_eq!(folding.respond("ééé").0, StatusCode::OK);
+        assert_eq!(folding.respond("bcd").0, StatusCode::BAD_REQUEST);
+        assert_eq!(contains_vowels("Ærø", "aeiouyæøå"), 2);
     }
 }
@@ -1,61 +1,201 @@
 #![allow(clippy::unwrap_used, clippy::significant_drop_tightening)]
 
-use anyhow::anyhow;
 use axum::{
+    async_trait,
     extract::{
-        ws::{Message::Text, WebSocket},
-        Path, State, WebSocketUpgrade,
+        ws::{
+            close_code, CloseFrame,
+            Message::{self, Binary, Text},
+            WebSocket,
+        },
+        Path, Query, State, WebSocketUpgrade,
+    },
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
     },
-    response::Response,
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use futures_util::{
+    sink::SinkExt,
+    stream::{self, StreamExt},
 };
-use futures_util::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fmt::Write,
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc, RwLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
     },
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError, Receiver, Sender},
+    mpsc, OnceCell,
 };
-use tokio::sync::broadcast::{self, Sender};
 use tracing::{info, warn};
+use uuid::Uuid;
 
 use super::AppError;
 
-pub fn router() -> Router {
-    let state = BirdAppState::new();
+const DEFAULT_HISTORY_SIZE: usize = 100;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+const DEFAULT_METRICS_FLUSH_SECS: u64 = 10;
+const DEFAULT_RATE_PER_SEC: f64 = 5.0;
+const DEFAULT_RATE_BURST: f64 = 10.0;
+const BUCKET_SWEEP_SECS: u64 = 60;
+const MAX_MESSAGE_LEN: usize = 128;
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+const DEFAULT_MAX_MISSED_PONGS: u32 = 2;
 
-    Router::new()
+pub fn router(pool: PgPool) -> Router {
+    let history_size = std::env::var("DAY19_HISTORY_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_HISTORY_SIZE);
+    let state = match std::env::var("DAY19_STORE").as_deref() {
+        Ok("postgres") => {
+            let flush_every = std::env::var("DAY19_METRICS_FLUSH_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_METRICS_FLUSH_SECS);
+            let metrics = Arc::new(Metrics::default());
+            metrics
+                .clone()
+                .persist(Arc::new(PgCounterStore::new(pool.clone())), flush_every);
+            BirdAppState::new(Arc::new(PgMessageStore::new(pool)), history_size, metrics)
+        }
+        // One past the replay window, so a client resuming from further
+        // back can be told it missed something.
+        _ => BirdAppState::new(
+            Arc::new(MemoryMessageStore::new(history_size + 1)),
+            history_size,
+            Arc::new(Metrics::default()),
+        ),
+    };
+
+    state.moderation.clone().sweep_idle_buckets();
+
+    let router = Router::new()
         .route("/19/ws/ping", get(ping))
         .route("/19/reset", post(reset))
         .route("/19/views", get(views))
+        .route("/19/metrics", get(metrics))
         .route("/19/ws/room/:room_id/user/:user", get(tweet))
-        .with_state(state)
+        .route("/19/rooms", get(list_rooms))
+        .route("/19/rooms/:room_id/users", get(room_users))
+        .route(
+            "/19/rooms/:room_id/private",
+            get(private_room).post(make_private).delete(make_public),
+        )
+        .route("/19/rooms/:room_id/sessions", post(open_session))
+        .route("/19/rooms/:room_id/invites", post(create_invite))
+        .route(
+            "/19/rooms/:room_id/invites/:token",
+            axum::routing::delete(revoke_invite),
+        )
+        .route("/19/users/:user/messages", post(post_direct))
+        .route("/19/rooms/:room_id/messages", get(room_messages))
+        .route("/19/sse/room/:room_id", get(sse_room))
+        .route("/19/room/:room_id/messages", post(post_message));
+
+    match std::env::var("DAY19_ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => router.merge(admin_router(token)).with_state(state),
+        _ => router.with_state(state),
+    }
+}
+
+/// Protocol-level keepalive: a ping every `interval`, and the socket is
+/// closed once `max_missed` of them in a row go unanswered.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    max_missed: u32,
+}
+
+impl Heartbeat {
+    fn from_env() -> Self {
+        Self {
+            interval: Duration::from_secs(
+                env_or("DAY19_PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL_SECS).max(1),
+            ),
+            // At least one ping gets a chance to be answered.
+            max_missed: env_or("DAY19_MAX_MISSED_PONGS", DEFAULT_MAX_MISSED_PONGS).max(1),
+        }
+    }
+
+    fn ticker(&self) -> tokio::time::Interval {
+        let mut ticker =
+            tokio::time::interval_at(tokio::time::Instant::now() + self.interval, self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker
+    }
+
+    /// Counts a ping about to be sent, or returns the close frame to send
+    /// instead when too many have gone unanswered.
+    fn beat(&self, missed: &AtomicU32) -> Result<Message, Message> {
+        if missed.fetch_add(1, Ordering::Relaxed) >= self.max_missed {
+            return Err(close_frame(close_code::AWAY, "Heartbeat timed out"));
+        }
+        Ok(Message::Ping(Vec::new()))
+    }
 }
 
-async fn ping(ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(handle_ping)
+fn close_frame(code: u16, reason: impl Into<Cow<'static, str>>) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
 }
 
-async fn handle_ping(mut socket: WebSocket) {
+async fn ping(ws: WebSocketUpgrade, State(state): State<BirdAppState>) -> Response {
+    ws.max_message_size(MAX_FRAME_BYTES)
+        .on_upgrade(move |socket| handle_ping(socket, state.heartbeat))
+}
+
+/// The `serve`/`ping` game is played with text frames, on top of the
+/// protocol-level heartbeat every socket gets.
+async fn handle_ping(mut socket: WebSocket, heartbeat: Heartbeat) {
     let mut started = false;
+    let mut ticker = heartbeat.ticker();
+    let missed = AtomicU32::new(0);
 
-    while let Some(msg) = socket.recv().await {
-        let Ok(msg) = msg else {
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => msg,
+            _ = ticker.tick() => {
+                let frame = heartbeat.beat(&missed);
+                let timed_out = frame.is_err();
+                if socket.send(frame.unwrap_or_else(|close| close)).await.is_err() || timed_out {
+                    return;
+                }
+                continue;
+            }
+        };
+        let Some(Ok(msg)) = msg else {
             return;
         };
+        missed.store(0, Ordering::Relaxed);
 
         if let Text(msg) = &msg {
             match msg.as_str() {
                 "serve" => started = true,
-                "ping" => {
-                    if started {
-                        socket
-                            .send(Text("pong".to_string()))
-                            .await
-                            .expect("Could not send message");
-                    }
+                "ping" if started => {
+                    let Ok(()) = socket.send(Text("pong".to_string())).await else {
+                        return;
+                    };
                 }
                 _ => {}
             }
@@ -63,139 +203,2485 @@ async fn handle_ping(mut socket: WebSocket) {
     }
 }
 
-async fn reset(State(state): State<BirdAppState>) -> Result<(), AppError> {
-    let _ = state.views.swap(0, Ordering::Relaxed);
+#[derive(Deserialize, Debug)]
+struct ViewsQuery {
+    room: Option<RoomId>,
+    user: Option<String>,
+}
+
+async fn reset(
+    State(state): State<BirdAppState>,
+    Query(query): Query<ViewsQuery>,
+) -> Result<(), AppError> {
+    state.metrics.reset(query.room);
 
     Ok(())
 }
 
-async fn views(State(state): State<BirdAppState>) -> String {
-    state.views.load(Ordering::Relaxed).to_string()
+/// Delivered message count: overall, or for one room or user. A private
+/// room's count needs a session for it.
+async fn views(
+    State(state): State<BirdAppState>,
+    Query(query): Query<ViewsQuery>,
+    Query(access): Query<RoomAccess>,
+) -> Response {
+    if let Some(room) = query.room {
+        if let Err(rejected) = state.private.admit(room, None, &access) {
+            return rejected.into_response();
+        }
+    }
+    let counts = state.metrics.counts.lock().unwrap();
+    let counters = match (query.room, query.user) {
+        (Some(room), _) => counts.rooms.get(&room).copied().unwrap_or_default(),
+        (None, Some(user)) => counts.users.get(&user).copied().unwrap_or_default(),
+        (None, None) => counts.total,
+    };
+    counters.delivered.to_string().into_response()
+}
+
+/// Sent and delivered message counts. `sent` is messages published, and
+/// `delivered` is copies of them pushed to connected clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Counters {
+    delivered: u64,
+    sent: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Counts {
+    total: Counters,
+    rooms: HashMap<RoomId, Counters>,
+    users: HashMap<String, Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    counts: Mutex<Counts>,
+    dirty: AtomicBool,
+}
+
+impl Metrics {
+    fn record_sent(&self, room: RoomId, user: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total.sent += 1;
+        counts.rooms.entry(room).or_default().sent += 1;
+        counts.users.entry(user.to_string()).or_default().sent += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn record_delivered(&self, room: RoomId, user: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total.delivered += 1;
+        counts.rooms.entry(room).or_default().delivered += 1;
+        counts.users.entry(user.to_string()).or_default().delivered += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Zeroes one room's counters, or everything when no room is given.
+    fn reset(&self, room: Option<RoomId>) {
+        let mut counts = self.counts.lock().unwrap();
+        match room {
+            Some(room) => {
+                counts.rooms.insert(room, Counters::default());
+            }
+            None => {
+                counts.total = Counters::default();
+                counts
+                    .rooms
+                    .values_mut()
+                    .for_each(|c| *c = Counters::default());
+                counts
+                    .users
+                    .values_mut()
+                    .for_each(|c| *c = Counters::default());
+            }
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Adds the stored counters to anything counted since startup, then
+    /// writes changes back every `flush_every` seconds.
+    fn persist(self: Arc<Self>, store: Arc<dyn CounterStore>, flush_every: u64) {
+        tokio::spawn(async move {
+            match store.load().await {
+                Ok(stored) => {
+                    let mut counts = self.counts.lock().unwrap();
+                    counts.total.sent += stored.total.sent;
+                    counts.total.delivered += stored.total.delivered;
+                    for (room, c) in stored.rooms {
+                        let counters = counts.rooms.entry(room).or_default();
+                        counters.sent += c.sent;
+                        counters.delivered += c.delivered;
+                    }
+                    for (user, c) in stored.users {
+                        let counters = counts.users.entry(user).or_default();
+                        counters.sent += c.sent;
+                        counters.delivered += c.delivered;
+                    }
+                }
+                Err(e) => warn!("Failed to load day19 metrics: {:?}", e),
+            }
+
+            let mut interval = tokio::time::interval(Duration::from_secs(flush_every.max(1)));
+            loop {
+                interval.tick().await;
+                if !self.dirty.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                let snapshot = self.counts.lock().unwrap().clone();
+                if let Err(e) = store.save(&snapshot).await {
+                    warn!("Failed to save day19 metrics: {:?}", e);
+                    self.dirty.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+trait CounterStore: Send + Sync + std::fmt::Debug {
+    async fn load(&self) -> anyhow::Result<Counts>;
+    async fn save(&self, counts: &Counts) -> anyhow::Result<()>;
+}
+
+#[derive(Debug)]
+struct PgCounterStore {
+    pool: PgPool,
+    schema: OnceCell<()>,
+}
+
+impl PgCounterStore {
+    fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            schema: OnceCell::new(),
+        }
+    }
+
+    async fn ensure_schema(&self) -> anyhow::Result<()> {
+        self.schema
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "CREATE TABLE IF NOT EXISTS bird_counters (
+                        scope TEXT NOT NULL,
+                        key TEXT NOT NULL,
+                        delivered BIGINT NOT NULL,
+                        sent BIGINT NOT NULL,
+                        PRIMARY KEY (scope, key)
+                    );",
+                )
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CounterStore for PgCounterStore {
+    async fn load(&self) -> anyhow::Result<Counts> {
+        self.ensure_schema().await?;
+        let rows = sqlx::query("SELECT scope, key, delivered, sent FROM bird_counters")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut counts = Counts::default();
+        for row in rows {
+            let counters = Counters {
+                delivered: row.get::<i64, _>("delivered") as u64,
+                sent: row.get::<i64, _>("sent") as u64,
+            };
+            let key: String = row.get("key");
+            match row.get::<String, _>("scope").as_str() {
+                "total" => counts.total = counters,
+                "room" => match key.parse() {
+                    Ok(room) => {
+                        counts.rooms.insert(room, counters);
+                    }
+                    Err(_) => warn!("Ignoring counters for bad room id {}", key),
+                },
+                "user" => {
+                    counts.users.insert(key, counters);
+                }
+                scope => warn!("Ignoring counters with unknown scope {}", scope),
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn save(&self, counts: &Counts) -> anyhow::Result<()> {
+        self.ensure_schema().await?;
+        let rows = std::iter::once(("total", String::new(), counts.total))
+            .chain(
+                counts
+                    .rooms
+                    .iter()
+                    .map(|(room, c)| ("room", room.to_string(), *c)),
+            )
+            .chain(
+                counts
+                    .users
+                    .iter()
+                    .map(|(user, c)| ("user", user.clone(), *c)),
+            );
+        let mut tx = self.pool.begin().await?;
+        for (scope, key, counters) in rows {
+            sqlx::query(
+                "INSERT INTO bird_counters (scope, key, delivered, sent)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (scope, key) DO UPDATE
+                SET delivered = EXCLUDED.delivered, sent = EXCLUDED.sent",
+            )
+            .bind(scope)
+            .bind(key)
+            .bind(counters.delivered as i64)
+            .bind(counters.sent as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counters and gauges in the Prometheus text exposition format. Private
+/// rooms are left out, as they are from the room list.
+async fn metrics(State(state): State<BirdAppState>) -> impl IntoResponse {
+    let counts = state.metrics.counts.lock().unwrap().clone();
+    let mut rooms = counts
+        .rooms
+        .into_iter()
+        .filter(|(room, _)| !state.private.is_private(*room))
+        .collect::<Vec<_>>();
+    rooms.sort_by_key(|(room, _)| *room);
+    let mut users = counts.users.into_iter().collect::<Vec<_>>();
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut members = state
+        .rooms
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(room, _)| !state.private.is_private(**room))
+        .map(|(room, room_state)| (*room, room_state.members.lock().unwrap().len()))
+        .collect::<Vec<_>>();
+    members.sort();
+
+    let mut body = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(body, "{}{} {}", name, labels, value);
+        }
+    };
+    family(
+        "bird_messages_sent_total",
+        "counter",
+        "Messages published to any room.",
+        vec![(String::new(), counts.total.sent)],
+    );
+    family(
+        "bird_messages_delivered_total",
+        "counter",
+        "Messages delivered to connected clients.",
+        vec![(String::new(), counts.total.delivered)],
+    );
+    family(
+        "bird_room_messages_sent_total",
+        "counter",
+        "Messages published per room.",
+        rooms
+            .iter()
+            .map(|(room, c)| (format!("{{room=\"{}\"}}", room), c.sent))
+            .collect(),
+    );
+    family(
+        "bird_room_messages_delivered_total",
+        "counter",
+        "Messages delivered per room.",
+        rooms
+            .iter()
+            .map(|(room, c)| (format!("{{room=\"{}\"}}", room), c.delivered))
+            .collect(),
+    );
+    family(
+        "bird_user_messages_sent_total",
+        "counter",
+        "Messages published per user.",
+        users
+            .iter()
+            .map(|(user, c)| (format!("{{user=\"{}\"}}", escape_label(user)), c.sent))
+            .collect(),
+    );
+    family(
+        "bird_user_messages_delivered_total",
+        "counter",
+        "Messages delivered per user.",
+        users
+            .iter()
+            .map(|(user, c)| (format!("{{user=\"{}\"}}", escape_label(user)), c.delivered))
+            .collect(),
+    );
+    family(
+        "bird_room_members",
+        "gauge",
+        "Users currently connected per room.",
+        members
+            .iter()
+            .map(|(room, n)| (format!("{{room=\"{}\"}}", room), *n as u64))
+            .collect(),
+    );
+
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
 }
 
 type RoomId = i32;
 
 #[derive(Clone, Debug)]
 struct BirdAppState {
-    views: Arc<AtomicU32>,
-    rooms: Arc<RwLock<HashMap<RoomId, RoomState>>>,
+    heartbeat: Heartbeat,
+    metrics: Arc<Metrics>,
+    moderation: Arc<Moderation>,
+    private: Arc<PrivateRooms>,
+    directory: Arc<Directory>,
+    rooms: Arc<RwLock<HashMap<RoomId, Arc<RoomState>>>>,
+    history: Arc<dyn MessageStore>,
+    history_size: usize,
+    /// Held across the store write while publishing to a room, so it can't
+    /// be a std mutex. Kept apart from `rooms` so posts to a room nobody is
+    /// in are ordered too; entries go once nobody is publishing.
+    publishing: Arc<Mutex<HashMap<RoomId, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Next message id, seeded from the store so ids keep increasing
+    /// across restarts when history is persisted.
+    next_id: Arc<OnceCell<AtomicU64>>,
 }
 
 #[derive(Debug)]
 struct RoomState {
-    sender: Sender<Tweet>,
+    sender: Sender<RoomEvent>,
+    /// Connected users and how many sockets each has open.
+    members: Mutex<HashMap<String, usize>>,
 }
 
 impl RoomState {
     fn new() -> Self {
         Self {
             sender: broadcast::channel(100).0,
+            members: Mutex::new(HashMap::new()),
         }
     }
 }
 
-impl BirdAppState {
-    fn new() -> Self {
-        Self {
-            views: Arc::new(AtomicU32::new(0)),
-            rooms: Arc::new(RwLock::new(HashMap::new())),
+/// Everything broadcast to a room: chat messages and presence changes.
+/// Serialises as the tagged envelope, e.g. `{"type":"message","id":..}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum RoomEvent {
+    Message(Tweet),
+    Join {
+        user: String,
+    },
+    Leave {
+        user: String,
+    },
+    Typing {
+        user: String,
+    },
+    Ban {
+        user: String,
+    },
+    /// Delivered to the recipient's connections and the sender's, never
+    /// to the room.
+    Direct(DirectMessage),
+    /// Sent only to the connection concerned, never broadcast.
+    Error {
+        code: &'static str,
+        message: String,
+    },
+    /// Sent only to a client that fell behind the room's buffer, or that
+    /// resumed from further back than the replay reaches. For a resume,
+    /// `missed` only counts the skipped messages still in the store.
+    Lagged {
+        missed: u64,
+    },
+    /// Sent first to each new connection: the bearer token for sending
+    /// direct messages over HTTP as its user.
+    Connected {
+        token: String,
+    },
+}
+
+/// Wire formats, negotiated through `Sec-WebSocket-Protocol`. Without
+/// one the server speaks `bird.v2` JSON; `bird.v1` is the original
+/// `{"user","message"}` shape, with no presence events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    V1,
+    V2,
+    V2MsgPack,
+}
+
+impl Protocol {
+    /// In order of preference when a client offers several.
+    const SUPPORTED: [&'static str; 3] = ["bird.v2.msgpack", "bird.v2", "bird.v1"];
+
+    /// Our most preferred protocol among those the client offers. Chosen
+    /// here rather than by the upgrade, which takes the client's first match.
+    fn preferred(headers: &HeaderMap) -> Option<&'static str> {
+        let offered: Vec<&str> = headers
+            .get_all("sec-websocket-protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        Self::SUPPORTED
+            .into_iter()
+            .find(|supported| offered.contains(supported))
+    }
+
+    fn negotiated(socket: &WebSocket) -> Self {
+        match socket.protocol().and_then(|p| p.to_str().ok()) {
+            Some("bird.v1") => Protocol::V1,
+            Some("bird.v2.msgpack") => Protocol::V2MsgPack,
+            _ => Protocol::V2,
+        }
+    }
+
+    fn encode(self, event: &RoomEvent) -> Option<Message> {
+        match (self, event) {
+            (Protocol::V1, RoomEvent::Message(tweet)) => Some(Text(
+                json!({"user": tweet.user, "message": tweet.message.message}).to_string(),
+            )),
+            (Protocol::V1, _) => None,
+            (Protocol::V2, event) => Some(Text(
+                serde_json::to_string(event).expect("events serialize to JSON"),
+            )),
+            (Protocol::V2MsgPack, event) => Some(Binary(
+                rmp_serde::to_vec_named(event).expect("events serialize to MessagePack"),
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-struct TweetInput {
+/// Frames a client may send. Plain `{"message": ..}` is still accepted.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientFrame {
+    Message(TweetInput),
+    Typing,
+    Direct {
+        to: String,
+        #[serde(flatten)]
+        message: TweetInput,
+    },
+}
+
+/// Why a client frame was refused, reported back as an `error` frame.
+#[derive(Debug)]
+struct Rejected {
+    code: &'static str,
     message: String,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-struct Tweet {
-    user: String,
-    message: TweetInput,
+impl Rejected {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Rejected> for RoomEvent {
+    fn from(value: Rejected) -> Self {
+        RoomEvent::Error {
+            code: value.code,
+            message: value.message,
+        }
+    }
+}
+
+impl IntoResponse for Rejected {
+    fn into_response(self) -> Response {
+        let status = match self.code {
+            "too_long" => StatusCode::PAYLOAD_TOO_LARGE,
+            "rate_limited" => StatusCode::TOO_MANY_REQUESTS,
+            "muted" | "banned" | "private" => StatusCode::FORBIDDEN,
+            "unknown_user" | "unknown_room" => StatusCode::NOT_FOUND,
+            "name_taken" => StatusCode::CONFLICT,
+            "not_stored" => StatusCode::INTERNAL_SERVER_ERROR,
+            "unauthorized" => StatusCode::UNAUTHORIZED,
+            "filtered" => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, Json(RoomEvent::from(self))).into_response()
+    }
+}
+
+impl ClientFrame {
+    fn parse(protocol: Protocol, msg: &Message) -> Option<Result<Self, Rejected>> {
+        let frame = match msg {
+            Text(text) => serde_json::from_str::<Self>(text)
+                .or_else(|_| serde_json::from_str::<TweetInput>(text).map(ClientFrame::Message))
+                .map_err(|e| Rejected::new("invalid", format!("Error parsing frame: {}", e))),
+            Binary(bytes) if protocol == Protocol::V2MsgPack => rmp_serde::from_slice(bytes)
+                .map_err(|e| Rejected::new("invalid", format!("Error parsing frame: {}", e))),
+            _ => return None,
+        };
+        Some(frame.and_then(|frame| {
+            match frame {
+                ClientFrame::Message(input) => input.validate().map(ClientFrame::Message),
+                ClientFrame::Direct { to, message } => message
+                    .validate()
+                    .map(|message| ClientFrame::Direct { to, message }),
+                frame => Ok(frame),
+            }
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-user rate limits, the word filter and per-room mutes and bans.
+/// Bans outlive the room itself, which is dropped once empty.
+#[derive(Debug)]
+struct Moderation {
+    rate_per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    blocked_words: RwLock<HashSet<String>>,
+    muted: RwLock<HashMap<RoomId, HashSet<String>>>,
+    banned: RwLock<HashMap<RoomId, HashSet<String>>>,
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
 }
 
-impl TryFrom<&String> for TweetInput {
-    type Error = AppError;
+impl Moderation {
+    fn from_env() -> Self {
+        let blocked_words = std::env::var("DAY19_BLOCKED_WORDS")
+            .map(|list| words(&list).collect())
+            .unwrap_or_default();
+        Self {
+            rate_per_sec: env_or("DAY19_RATE_PER_SEC", DEFAULT_RATE_PER_SEC),
+            burst: env_or("DAY19_RATE_BURST", DEFAULT_RATE_BURST),
+            buckets: Mutex::new(HashMap::new()),
+            blocked_words: RwLock::new(blocked_words),
+            muted: RwLock::new(HashMap::new()),
+            banned: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the user's bucket, refilled at `rate_per_sec`
+    /// up to `burst`.
+    fn take_token(&self, user: &str) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(user.to_string()).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate_per_sec).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Drops buckets that have refilled to `burst`, which a fresh bucket
+    /// would start at anyway.
+    fn drop_full_buckets(&self, now: Instant) {
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate_per_sec < self.burst
+        });
+    }
+
+    /// Keeps the buckets from growing with every user name ever seen.
+    fn sweep_idle_buckets(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(BUCKET_SWEEP_SECS));
+            loop {
+                interval.tick().await;
+                self.drop_full_buckets(Instant::now());
+            }
+        });
+    }
+
+    fn listed(list: &RwLock<HashMap<RoomId, HashSet<String>>>, room: RoomId, user: &str) -> bool {
+        list.read()
+            .unwrap()
+            .get(&room)
+            .is_some_and(|users| users.contains(user))
+    }
+
+    fn set_listed(
+        list: &RwLock<HashMap<RoomId, HashSet<String>>>,
+        room: RoomId,
+        user: &str,
+        listed: bool,
+    ) {
+        let mut list = list.write().unwrap();
+        if listed {
+            list.entry(room).or_default().insert(user.to_string());
+        } else if let Some(users) = list.get_mut(&room) {
+            users.remove(user);
+            if users.is_empty() {
+                list.remove(&room);
+            }
+        }
+    }
+
+    fn is_banned(&self, room: RoomId, user: &str) -> bool {
+        Self::listed(&self.banned, room, user)
+    }
 
-    fn try_from(value: &String) -> Result<Self, Self::Error> {
-        let tweet_input = serde_json::from_str::<Self>(value)
-            .map_err(|e| anyhow!("Error parsing TweetInput: {}", e))?;
+    /// Every frame costs a token; messages are also checked for mutes and
+    /// blocked words.
+    fn check(
+        &self,
+        room: RoomId,
+        user: &str,
+        message: Option<&TweetInput>,
+    ) -> Result<(), Rejected> {
+        if !self.take_token(user) {
+            return Err(Rejected::new("rate_limited", "Slow down"));
+        }
+        let Some(input) = message else {
+            return Ok(());
+        };
+        if Self::listed(&self.muted, room, user) {
+            return Err(Rejected::new("muted", "You are muted in this room"));
+        }
+        self.filter(input)
+    }
 
-        if tweet_input.message.len() > 128 {
-            return Err(anyhow!("Message length cannot be over 128").into());
+    /// Direct messages are rate limited and filtered, but room mutes don't
+    /// apply to them.
+    fn check_direct(&self, user: &str, input: &TweetInput) -> Result<(), Rejected> {
+        if !self.take_token(user) {
+            return Err(Rejected::new("rate_limited", "Slow down"));
         }
+        self.filter(input)
+    }
 
-        Ok(tweet_input)
+    fn filter(&self, input: &TweetInput) -> Result<(), Rejected> {
+        let blocked_words = self.blocked_words.read().unwrap();
+        if words(&input.message).any(|word| blocked_words.contains(&word)) {
+            return Err(Rejected::new("filtered", "Message contains a blocked word"));
+        }
+        Ok(())
     }
 }
 
-impl From<Tweet> for String {
-    fn from(value: Tweet) -> Self {
-        format!(
-            r#"{{
-      "user": "{}",
-      "message": "{}"
-    }}"#,
-            value.user, value.message.message
+fn admin_router(token: String) -> Router<BirdAppState> {
+    Router::new()
+        .route("/19/admin/filter", get(get_filter).put(put_filter))
+        .route("/19/admin/rooms/:room_id/moderation", get(room_moderation))
+        .route(
+            "/19/admin/rooms/:room_id/mutes/:user",
+            post(mute).delete(unmute),
         )
+        .route(
+            "/19/admin/rooms/:room_id/bans/:user",
+            post(ban).delete(unban),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_admin,
+        ))
+}
+
+async fn require_admin<B>(
+    State(token): State<Arc<str>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorized = request
+        .headers()
+        .typed_get::<Authorization<Bearer>>()
+        .is_some_and(|auth| auth.token() == &*token);
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
     }
+    next.run(request).await
 }
 
-async fn tweet(
-    ws: WebSocketUpgrade,
-    Path((room, user)): Path<(i32, String)>,
+async fn get_filter(State(state): State<BirdAppState>) -> Json<Vec<String>> {
+    let mut words = state
+        .moderation
+        .blocked_words
+        .read()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    words.sort();
+    Json(words)
+}
+
+async fn put_filter(
     State(state): State<BirdAppState>,
-) -> Response {
-    ws.on_upgrade(move |c| handle_tweet(c, room, user, Arc::new(state)))
+    Json(list): Json<Vec<String>>,
+) -> StatusCode {
+    *state.moderation.blocked_words.write().unwrap() =
+        list.iter().flat_map(|entry| words(entry)).collect();
+    StatusCode::NO_CONTENT
 }
 
-async fn handle_tweet(socket: WebSocket, room: i32, user: String, state: Arc<BirdAppState>) {
-    let (mut sender, mut receiver) = socket.split();
-    #[allow(unused_assignments)]
-    let mut room_sender = None::<Sender<Tweet>>;
+#[derive(Serialize, Debug)]
+struct RoomModeration {
+    muted: Vec<String>,
+    banned: Vec<String>,
+}
+
+async fn room_moderation(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+) -> Json<RoomModeration> {
+    let sorted = |list: &RwLock<HashMap<RoomId, HashSet<String>>>| {
+        let mut users = list
+            .read()
+            .unwrap()
+            .get(&room)
+            .map(|users| users.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        users.sort();
+        users
+    };
+    Json(RoomModeration {
+        muted: sorted(&state.moderation.muted),
+        banned: sorted(&state.moderation.banned),
+    })
+}
+
+async fn mute(
+    State(state): State<BirdAppState>,
+    Path((room, user)): Path<(RoomId, String)>,
+) -> StatusCode {
+    Moderation::set_listed(&state.moderation.muted, room, &user, true);
+    StatusCode::NO_CONTENT
+}
+
+async fn unmute(
+    State(state): State<BirdAppState>,
+    Path((room, user)): Path<(RoomId, String)>,
+) -> StatusCode {
+    Moderation::set_listed(&state.moderation.muted, room, &user, false);
+    StatusCode::NO_CONTENT
+}
 
+/// Bans the user from the room and disconnects any sockets they have open.
+async fn ban(
+    State(state): State<BirdAppState>,
+    Path((room, user)): Path<(RoomId, String)>,
+) -> StatusCode {
+    Moderation::set_listed(&state.moderation.banned, room, &user, true);
     if let Some(room_state) = state.rooms.read().unwrap().get(&room) {
-        room_sender = Some(room_state.sender.clone());
+        let _ = room_state.sender.send(RoomEvent::Ban { user });
     }
+    StatusCode::NO_CONTENT
+}
+
+async fn unban(
+    State(state): State<BirdAppState>,
+    Path((room, user)): Path<(RoomId, String)>,
+) -> StatusCode {
+    Moderation::set_listed(&state.moderation.banned, room, &user, false);
+    StatusCode::NO_CONTENT
+}
+
+/// How a client gets back into a private room: a session token from
+/// `POST /19/rooms/:room_id/sessions`, or the owner's management token.
+#[derive(Deserialize, Debug, Default)]
+struct RoomAccess {
+    session: Option<String>,
+}
+
+#[derive(Debug)]
+struct PrivateRoom {
+    owner: String,
+    /// Bearer token for managing the room, handed out on creation. It also
+    /// lets the owner in.
+    token: String,
+    secret: Option<[u8; 32]>,
+    /// Outstanding single-use invites, optionally for a named user.
+    invites: HashMap<String, Option<String>>,
+    /// Session tokens handed out on admission, and whose they are.
+    sessions: HashMap<String, String>,
+}
+
+/// Rooms that need a secret or an invite to get into. Like bans, this
+/// outlives the room itself; public rooms have no entry.
+#[derive(Debug, Default)]
+struct PrivateRooms {
+    rooms: RwLock<HashMap<RoomId, PrivateRoom>>,
+}
+
+fn new_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+fn hash_secret(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
 
-    if room_sender.is_none() {
-        let mut rooms = state.rooms.write().unwrap();
-        let room_state = rooms.entry(room).or_insert_with(RoomState::new);
-        room_sender = Some(room_state.sender.clone());
+impl PrivateRooms {
+    fn is_private(&self, room: RoomId) -> bool {
+        self.rooms.read().unwrap().contains_key(&room)
     }
 
-    let room_sender = room_sender.unwrap();
-    let mut room_receiver = room_sender.subscribe();
+    /// Makes the room private, returning its management token, or `None`
+    /// when it already is.
+    fn create(&self, room: RoomId, owner: String, secret: Option<&str>) -> Option<String> {
+        let mut rooms = self.rooms.write().unwrap();
+        if rooms.contains_key(&room) {
+            return None;
+        }
+        let token = new_token();
+        rooms.insert(
+            room,
+            PrivateRoom {
+                owner,
+                token: token.clone(),
+                secret: secret.map(hash_secret),
+                invites: HashMap::new(),
+                sessions: HashMap::new(),
+            },
+        );
+        Some(token)
+    }
 
-    let mut send = tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            let Ok(msg) = msg else {
-                return;
-            };
+    /// Checks the secret or invite and hands the user a session token for
+    /// the room. An invite is used up once it has admitted someone.
+    fn open_session(
+        &self,
+        room: RoomId,
+        user: &str,
+        secret: Option<&str>,
+        invite: Option<&str>,
+    ) -> Result<String, Rejected> {
+        let mut rooms = self.rooms.write().unwrap();
+        let Some(private) = rooms.get_mut(&room) else {
+            return Err(Rejected::new("unknown_room", "This room isn't private"));
+        };
+        let secret_ok = match (&private.secret, secret) {
+            (Some(expected), Some(secret)) => hash_secret(secret) == *expected,
+            _ => false,
+        };
+        let invite_ok = invite.is_some_and(|invite| {
+            private
+                .invites
+                .get(invite)
+                .is_some_and(|invited| invited.as_deref().map_or(true, |invited| invited == user))
+        });
+        if !secret_ok && !invite_ok {
+            return Err(Rejected::new("private", "This room is private"));
+        }
+        if invite_ok {
+            private.invites.remove(invite.unwrap_or_default());
+        }
+        let session = new_token();
+        private.sessions.insert(session.clone(), user.to_string());
+        Ok(session)
+    }
 
-            if let Text(text) = &msg {
-                match TweetInput::try_from(text) {
-                    Ok(message) => {
-                        info!("Parsed {:?}", message);
-                        let user = user.clone();
-                        let _ = room_sender.send(Tweet { user, message }).unwrap();
-                    }
-                    Err(e) => warn!("Failed to parse TweetInput: {:?}", e),
-                }
+    /// Lets the user in if the room is public or they bring a session
+    /// token issued to them; the management token stands for the owner.
+    /// Without a user name any valid token will do.
+    fn admit(&self, room: RoomId, user: Option<&str>, access: &RoomAccess) -> Result<(), Rejected> {
+        let rooms = self.rooms.read().unwrap();
+        let Some(private) = rooms.get(&room) else {
+            return Ok(());
+        };
+        let holder = access.session.as_deref().and_then(|session| {
+            if session == private.token {
+                Some(&private.owner)
+            } else {
+                private.sessions.get(session)
             }
+        });
+        match holder {
+            Some(holder) if user.map_or(true, |user| user == holder) => Ok(()),
+            _ => Err(Rejected::new("private", "This room is private")),
         }
-    });
+    }
 
-    let mut receive = tokio::spawn(async move {
-        while let Ok(msg) = room_receiver.recv().await {
-            let msg_str: String = msg.into();
-            let _ = state.views.fetch_add(1, Ordering::Relaxed);
-            sender.send(Text(msg_str)).await.unwrap();
+    /// Checks the `Authorization: Bearer` management token for the room.
+    fn authorize(&self, room: RoomId, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let rooms = self.rooms.read().unwrap();
+        let private = rooms.get(&room).ok_or(StatusCode::NOT_FOUND)?;
+        let authorized = headers
+            .typed_get::<Authorization<Bearer>>()
+            .is_some_and(|auth| auth.token() == private.token);
+        if !authorized {
+            return Err(StatusCode::UNAUTHORIZED);
         }
-    });
+        Ok(())
+    }
+}
 
-    tokio::select! {
-        _ = (&mut send) => receive.abort(),
-        _ = (&mut receive) => send.abort(),
-    };
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DirectMessage {
+    from: String,
+    to: String,
+    #[serde(flatten)]
+    message: TweetInput,
+    ts: i64,
+}
+
+/// Every open websocket and SSE connection by user name, so direct
+/// messages reach a user whichever room they're in. Each connection is
+/// keyed by a token it's told on connecting, which is what lets it send
+/// direct messages over HTTP. A name belongs to whoever connected with it
+/// first until all their connections close; opening another takes one of
+/// its tokens.
+#[derive(Debug, Default)]
+struct Directory {
+    mailboxes: Mutex<HashMap<String, HashMap<String, mpsc::Sender<RoomEvent>>>>,
+}
+
+/// A connection's entry in the directory, removed on drop.
+#[derive(Debug)]
+struct Mailbox {
+    directory: Arc<Directory>,
+    user: String,
+    token: String,
+}
+
+fn check_claim(
+    held: Option<&HashMap<String, mpsc::Sender<RoomEvent>>>,
+    claim: Option<&str>,
+) -> Result<(), Rejected> {
+    match held {
+        Some(connections) if !claim.is_some_and(|token| connections.contains_key(token)) => Err(
+            Rejected::new("name_taken", "This name is connected elsewhere"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+impl Directory {
+    /// Whether a connection could be opened for the user with `claim`.
+    fn claimable(&self, user: &str, claim: Option<&str>) -> Result<(), Rejected> {
+        check_claim(self.mailboxes.lock().unwrap().get(user), claim)
+    }
+
+    fn register(
+        self: &Arc<Self>,
+        user: &str,
+        claim: Option<&str>,
+        sender: mpsc::Sender<RoomEvent>,
+    ) -> Result<Mailbox, Rejected> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        check_claim(mailboxes.get(user), claim)?;
+        let token = new_token();
+        mailboxes
+            .entry(user.to_string())
+            .or_default()
+            .insert(token.clone(), sender);
+        Ok(Mailbox {
+            directory: self.clone(),
+            user: user.to_string(),
+            token,
+        })
+    }
+
+    /// Whose connection the token belongs to, while it's open.
+    fn user(&self, token: &str) -> Option<String> {
+        let mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes
+            .iter()
+            .find(|(_, connections)| connections.contains_key(token))
+            .map(|(user, _)| user.clone())
+    }
+
+    /// Hands the event to each of the user's connections, returning
+    /// whether they have any.
+    fn deliver(&self, user: &str, event: &RoomEvent) -> bool {
+        let mailboxes = self.mailboxes.lock().unwrap();
+        let Some(connections) = mailboxes.get(user) else {
+            return false;
+        };
+        for sender in connections.values() {
+            let _ = sender.try_send(event.clone());
+        }
+        true
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        let mut mailboxes = self.directory.mailboxes.lock().unwrap();
+        if let Some(connections) = mailboxes.get_mut(&self.user) {
+            connections.remove(&self.token);
+            if connections.is_empty() {
+                mailboxes.remove(&self.user);
+            }
+        }
+    }
+}
+
+impl BirdAppState {
+    fn new(history: Arc<dyn MessageStore>, history_size: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            heartbeat: Heartbeat::from_env(),
+            metrics,
+            moderation: Arc::new(Moderation::from_env()),
+            private: Arc::new(PrivateRooms::default()),
+            directory: Arc::new(Directory::default()),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            history,
+            history_size,
+            publishing: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Adds the user to the room, creating it if needed, and announces
+    /// their first connection. Joining and leaving both hold the rooms lock
+    /// so an emptied room can't be dropped while someone is joining it, and
+    /// admission is checked under it so the room can't turn private either.
+    fn join(
+        &self,
+        room: RoomId,
+        user: &str,
+        access: &RoomAccess,
+    ) -> Result<(Arc<RoomState>, Receiver<RoomEvent>), Rejected> {
+        let mut rooms = self.rooms.write().unwrap();
+        self.private.admit(room, Some(user), access)?;
+        let room_state = rooms
+            .entry(room)
+            .or_insert_with(|| Arc::new(RoomState::new()))
+            .clone();
+        let receiver = room_state.sender.subscribe();
+        let connections = {
+            let mut members = room_state.members.lock().unwrap();
+            let connections = members.entry(user.to_string()).or_default();
+            *connections += 1;
+            *connections
+        };
+        if connections == 1 {
+            let _ = room_state.sender.send(RoomEvent::Join {
+                user: user.to_string(),
+            });
+        }
+        Ok((room_state, receiver))
+    }
+
+    /// Removes one of the user's connections, announcing when their last
+    /// one goes and dropping the room once nobody is left.
+    fn leave(&self, room: RoomId, user: &str) {
+        let mut rooms = self.rooms.write().unwrap();
+        let Some(room_state) = rooms.get(&room) else {
+            return;
+        };
+        let (gone, empty) = {
+            let mut members = room_state.members.lock().unwrap();
+            let gone = match members.get_mut(user) {
+                Some(connections) if *connections > 1 => {
+                    *connections -= 1;
+                    false
+                }
+                Some(_) => {
+                    members.remove(user);
+                    true
+                }
+                None => false,
+            };
+            (gone, members.is_empty())
+        };
+        if gone {
+            let _ = room_state.sender.send(RoomEvent::Leave {
+                user: user.to_string(),
+            });
+        }
+        if empty {
+            rooms.remove(&room);
+        }
+    }
+
+    async fn ids(&self) -> anyhow::Result<&AtomicU64> {
+        self.next_id
+            .get_or_try_init(|| async { Ok(AtomicU64::new(self.history.last_id().await? + 1)) })
+            .await
+    }
+
+    /// The room's publish lock, shared by everyone using it at the moment.
+    fn publish_lock(&self, room: RoomId) -> Arc<tokio::sync::Mutex<()>> {
+        self.publishing
+            .lock()
+            .unwrap()
+            .entry(room)
+            .or_default()
+            .clone()
+    }
+
+    /// Forgets the room's publish lock once nobody else has it.
+    fn release_publish_lock(&self, room: RoomId, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut publishing = self.publishing.lock().unwrap();
+        // Only the map and this call hold the lock, so nobody is waiting.
+        if Arc::strong_count(&lock) == 2 {
+            publishing.remove(&room);
+        }
+    }
+
+    /// Checks the user is let in, then stamps the message with the next
+    /// id, stores it and broadcasts it, all under the room's publish lock,
+    /// which making the room private takes too. Ids are stored and
+    /// delivered in order, and since the room is looked up only after the
+    /// message is stored, anyone who joins too late to get it live has it
+    /// in their replay. With nobody connected the message only goes to
+    /// history.
+    async fn publish(
+        &self,
+        room: RoomId,
+        user: String,
+        access: &RoomAccess,
+        message: TweetInput,
+    ) -> Result<Tweet, Rejected> {
+        let lock = self.publish_lock(room);
+        let guard = lock.lock().await;
+        let published = async {
+            self.private.admit(room, Some(&user), access)?;
+            let stored = async {
+                let ids = self.ids().await?;
+                let tweet = Tweet {
+                    id: ids.fetch_add(1, Ordering::Relaxed),
+                    user,
+                    message,
+                    ts: chrono::Utc::now().timestamp_millis(),
+                };
+                self.history.append(room, &tweet).await?;
+                anyhow::Ok(tweet)
+            };
+            let tweet = stored.await.map_err(|e| {
+                warn!("Failed to store message: {:?}", e);
+                Rejected::new("not_stored", "Failed to store the message")
+            })?;
+            if let Some(room_state) = self.rooms.read().unwrap().get(&room) {
+                let _ = room_state.sender.send(RoomEvent::Message(tweet.clone()));
+            }
+            self.metrics.record_sent(room, &tweet.user);
+            Ok(tweet)
+        }
+        .await;
+        drop(guard);
+        self.release_publish_lock(room, lock);
+        published
+    }
+
+    /// History to replay to a new subscriber: the latest messages, or those
+    /// after `after` when resuming, along with how many messages after
+    /// `after` are too old to replay.
+    async fn replay(&self, room: RoomId, after: Option<u64>) -> (Vec<Tweet>, u64) {
+        let replay = async {
+            let mut replay = self.history.page(room, None, self.history_size).await?;
+            let mut missed = 0;
+            if let Some(after) = after {
+                if let Some(oldest) = replay.first().filter(|oldest| oldest.id > after) {
+                    let older = self.history.page(room, Some(oldest.id), MAX_PAGE_SIZE);
+                    missed = older.await?.iter().filter(|tweet| tweet.id > after).count();
+                }
+                replay.retain(|tweet| tweet.id > after);
+            }
+            anyhow::Ok((replay, missed as u64))
+        };
+        replay.await.unwrap_or_else(|e| {
+            warn!("Failed to load history for room {}: {:?}", room, e);
+            (Vec::new(), 0)
+        })
+    }
+
+    /// Acts on one frame from a websocket client. A refused frame comes
+    /// back as the error to send to that client.
+    async fn handle_frame(
+        &self,
+        room: RoomId,
+        room_state: &RoomState,
+        user: &str,
+        access: &RoomAccess,
+        protocol: Protocol,
+        msg: &Message,
+    ) -> Result<(), Rejected> {
+        let frame = match ClientFrame::parse(protocol, msg) {
+            Some(Ok(frame)) => frame,
+            Some(Err(rejected)) => {
+                warn!("Rejected frame from {}: {:?}", user, rejected);
+                return Err(rejected);
+            }
+            None => return Ok(()),
+        };
+        match &frame {
+            ClientFrame::Message(message) => self.moderation.check(room, user, Some(message)),
+            ClientFrame::Typing => self.moderation.check(room, user, None),
+            ClientFrame::Direct { message, .. } => self.moderation.check_direct(user, message),
+        }?;
+        match frame {
+            ClientFrame::Direct { to, message } => {
+                self.send_direct(user.to_string(), to, message)?;
+            }
+            ClientFrame::Typing => {
+                let _ = room_state.sender.send(RoomEvent::Typing {
+                    user: user.to_string(),
+                });
+            }
+            ClientFrame::Message(message) => {
+                info!("Parsed {:?}", message);
+                self.publish(room, user.to_string(), access, message)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes a direct message by user name, copying it to the sender's
+    /// other connections too.
+    fn send_direct(
+        &self,
+        from: String,
+        to: String,
+        message: TweetInput,
+    ) -> Result<DirectMessage, Rejected> {
+        let direct = DirectMessage {
+            from,
+            to,
+            message,
+            ts: chrono::Utc::now().timestamp_millis(),
+        };
+        let event = RoomEvent::Direct(direct.clone());
+        if !self.directory.deliver(&direct.to, &event) {
+            return Err(Rejected::new(
+                "unknown_user",
+                format!("{} is not connected", direct.to),
+            ));
+        }
+        if direct.from != direct.to {
+            self.directory.deliver(&direct.from, &event);
+        }
+        Ok(direct)
+    }
+}
+
+/// Per-room message history, used for replay on join and for paging.
+#[async_trait]
+trait MessageStore: Send + Sync + std::fmt::Debug {
+    async fn append(&self, room: RoomId, tweet: &Tweet) -> anyhow::Result<()>;
+    /// Up to `limit` messages older than `before`, oldest first.
+    async fn page(
+        &self,
+        room: RoomId,
+        before: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Tweet>>;
+    async fn last_id(&self) -> anyhow::Result<u64>;
+}
+
+/// Keeps the last `capacity` messages of every room.
+#[derive(Debug)]
+struct MemoryMessageStore {
+    capacity: usize,
+    rooms: Mutex<HashMap<RoomId, VecDeque<Tweet>>>,
+}
+
+impl MemoryMessageStore {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryMessageStore {
+    async fn append(&self, room: RoomId, tweet: &Tweet) -> anyhow::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = rooms.entry(room).or_default();
+        history.push_back(tweet.clone());
+        while history.len() > self.capacity {
+            history.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn page(
+        &self,
+        room: RoomId,
+        before: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(history) = rooms.get(&room) else {
+            return Ok(Vec::new());
+        };
+        let mut page = history
+            .iter()
+            .rev()
+            .filter(|tweet| before.map_or(true, |before| tweet.id < before))
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        page.reverse();
+        Ok(page)
+    }
+
+    async fn last_id(&self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+}
+
+/// Keeps every message in Postgres so history and ids survive restarts.
+#[derive(Debug)]
+struct PgMessageStore {
+    pool: PgPool,
+    schema: OnceCell<()>,
+}
+
+impl PgMessageStore {
+    fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            schema: OnceCell::new(),
+        }
+    }
+
+    async fn ensure_schema(&self) -> anyhow::Result<()> {
+        self.schema
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "CREATE TABLE IF NOT EXISTS bird_messages (
+                        id BIGINT PRIMARY KEY,
+                        room INTEGER NOT NULL,
+                        username TEXT NOT NULL,
+                        message TEXT NOT NULL,
+                        sent_at BIGINT NOT NULL
+                    );",
+                )
+                .execute(&self.pool)
+                .await?;
+                sqlx::query(
+                    "CREATE INDEX IF NOT EXISTS bird_messages_room_id
+                    ON bird_messages (room, id);",
+                )
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for PgMessageStore {
+    async fn append(&self, room: RoomId, tweet: &Tweet) -> anyhow::Result<()> {
+        self.ensure_schema().await?;
+        sqlx::query(
+            "INSERT INTO bird_messages (id, room, username, message, sent_at)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(tweet.id as i64)
+        .bind(room)
+        .bind(&tweet.user)
+        .bind(&tweet.message.message)
+        .bind(tweet.ts)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn page(
+        &self,
+        room: RoomId,
+        before: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        self.ensure_schema().await?;
+        let rows = sqlx::query(
+            "SELECT id, username, message, sent_at FROM bird_messages
+            WHERE room = $1 AND id < $2
+            ORDER BY id DESC
+            LIMIT $3",
+        )
+        .bind(room)
+        .bind(before.map_or(i64::MAX, |before| before as i64))
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .rev()
+            .map(|row| Tweet {
+                id: row.get::<i64, _>("id") as u64,
+                user: row.get("username"),
+                message: TweetInput {
+                    message: row.get("message"),
+                },
+                ts: row.get("sent_at"),
+            })
+            .collect())
+    }
+
+    async fn last_id(&self) -> anyhow::Result<u64> {
+        self.ensure_schema().await?;
+        let row = sqlx::query("SELECT COALESCE(MAX(id), 0) AS id FROM bird_messages")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get::<i64, _>("id") as u64)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RoomSummary {
+    id: RoomId,
+    members: usize,
+}
+
+async fn list_rooms(State(state): State<BirdAppState>) -> Json<Vec<RoomSummary>> {
+    let mut rooms = state
+        .rooms
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| !state.private.is_private(**id))
+        .map(|(id, room_state)| RoomSummary {
+            id: *id,
+            members: room_state.members.lock().unwrap().len(),
+        })
+        .collect::<Vec<_>>();
+    rooms.sort_by_key(|room| room.id);
+    Json(rooms)
+}
+
+#[derive(Deserialize, Debug)]
+struct Viewer {
+    user: Option<String>,
+}
+
+async fn room_users(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Query(viewer): Query<Viewer>,
+    Query(access): Query<RoomAccess>,
+) -> Response {
+    if let Err(rejected) = state.private.admit(room, viewer.user.as_deref(), &access) {
+        return rejected.into_response();
+    }
+    let rooms = state.rooms.read().unwrap();
+    let Some(room_state) = rooms.get(&room) else {
+        return (StatusCode::NOT_FOUND, "Unknown room").into_response();
+    };
+    let mut users = room_state
+        .members
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    users.sort();
+    Json(users).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct PageQuery {
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+async fn room_messages(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Query(query): Query<PageQuery>,
+    Query(viewer): Query<Viewer>,
+    Query(access): Query<RoomAccess>,
+) -> Result<Response, AppError> {
+    if let Err(rejected) = state.private.admit(room, viewer.user.as_deref(), &access) {
+        return Ok(rejected.into_response());
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    Ok(Json(state.history.page(room, query.before, limit).await?).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+struct PrivateRoomRequest {
+    owner: String,
+    secret: Option<String>,
+}
+
+/// Makes a room private. Only a room nobody is in can be claimed. The
+/// response carries the token for managing it, which isn't shown again.
+/// Joining and publishing check admission under the same locks, so nobody
+/// gets in on the strength of the room having been public.
+async fn make_private(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Json(request): Json<PrivateRoomRequest>,
+) -> Response {
+    let lock = state.publish_lock(room);
+    let guard = lock.lock().await;
+    let created = {
+        let rooms = state.rooms.read().unwrap();
+        let occupied = rooms
+            .get(&room)
+            .is_some_and(|room_state| !room_state.members.lock().unwrap().is_empty());
+        if occupied {
+            Err("Room has members")
+        } else {
+            state
+                .private
+                .create(room, request.owner.clone(), request.secret.as_deref())
+                .ok_or("Room is already private")
+        }
+    };
+    drop(guard);
+    state.release_publish_lock(room, lock);
+    match created {
+        Ok(token) => (
+            StatusCode::CREATED,
+            Json(json!({"room": room, "owner": request.owner, "token": token})),
+        )
+            .into_response(),
+        Err(reason) => (StatusCode::CONFLICT, reason).into_response(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PrivateRoomInfo {
+    owner: String,
+    has_secret: bool,
+    invites: Vec<Invite>,
+    admitted: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Invite {
+    token: String,
+    user: Option<String>,
+}
+
+async fn private_room(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    headers: HeaderMap,
+) -> Result<Json<PrivateRoomInfo>, StatusCode> {
+    state.private.authorize(room, &headers)?;
+    let rooms = state.private.rooms.read().unwrap();
+    let private = rooms.get(&room).ok_or(StatusCode::NOT_FOUND)?;
+    let mut invites = private
+        .invites
+        .iter()
+        .map(|(token, user)| Invite {
+            token: token.clone(),
+            user: user.clone(),
+        })
+        .collect::<Vec<_>>();
+    invites.sort_by(|a, b| a.token.cmp(&b.token));
+    let mut admitted = private.sessions.values().cloned().collect::<Vec<_>>();
+    admitted.sort();
+    admitted.dedup();
+    Ok(Json(PrivateRoomInfo {
+        owner: private.owner.clone(),
+        has_secret: private.secret.is_some(),
+        invites,
+        admitted,
+    }))
+}
+
+async fn make_public(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if let Err(status) = state.private.authorize(room, &headers) {
+        return status;
+    }
+    state.private.rooms.write().unwrap().remove(&room);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize, Debug)]
+struct SessionRequest {
+    user: String,
+    secret: Option<String>,
+    invite: Option<String>,
+}
+
+/// Trades the room's secret or an invite for a session token, which is
+/// what gets the user in from then on, as `?session=`.
+async fn open_session(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Json(request): Json<SessionRequest>,
+) -> Response {
+    match state.private.open_session(
+        room,
+        &request.user,
+        request.secret.as_deref(),
+        request.invite.as_deref(),
+    ) {
+        Ok(session) => (
+            StatusCode::CREATED,
+            Json(json!({"room": room, "user": request.user, "session": session})),
+        )
+            .into_response(),
+        Err(rejected) => rejected.into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct InviteRequest {
+    user: Option<String>,
+}
+
+/// Issues a single-use invite, for anyone or only for the named user.
+async fn create_invite(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    headers: HeaderMap,
+    request: Option<Json<InviteRequest>>,
+) -> Result<(StatusCode, Json<Invite>), StatusCode> {
+    state.private.authorize(room, &headers)?;
+    let user = request.unwrap_or_default().0.user;
+    let token = new_token();
+    let mut rooms = state.private.rooms.write().unwrap();
+    let private = rooms.get_mut(&room).ok_or(StatusCode::NOT_FOUND)?;
+    private.invites.insert(token.clone(), user.clone());
+    Ok((StatusCode::CREATED, Json(Invite { token, user })))
+}
+
+async fn revoke_invite(
+    State(state): State<BirdAppState>,
+    Path((room, token)): Path<(RoomId, String)>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if let Err(status) = state.private.authorize(room, &headers) {
+        return status;
+    }
+    let mut rooms = state.private.rooms.write().unwrap();
+    match rooms
+        .get_mut(&room)
+        .and_then(|private| private.invites.remove(&token))
+    {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct TweetInput {
+    message: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Tweet {
+    id: u64,
+    user: String,
+    #[serde(flatten)]
+    message: TweetInput,
+    ts: i64,
+}
+
+impl TweetInput {
+    fn validate(self) -> Result<Self, Rejected> {
+        if self.message.len() > MAX_MESSAGE_LEN {
+            return Err(Rejected::new(
+                "too_long",
+                format!("Message length cannot be over {}", MAX_MESSAGE_LEN),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Opening another connection under a user name that's already connected
+/// takes the `token` from one of its `connected` frames.
+#[derive(Deserialize, Debug, Default)]
+struct NameClaim {
+    token: Option<String>,
+}
+
+async fn tweet(
+    ws: WebSocketUpgrade,
+    Path((room, user)): Path<(i32, String)>,
+    Query(access): Query<RoomAccess>,
+    Query(claim): Query<NameClaim>,
+    State(state): State<BirdAppState>,
+    headers: HeaderMap,
+) -> Response {
+    if state.moderation.is_banned(room, &user) {
+        return (StatusCode::FORBIDDEN, "Banned from this room").into_response();
+    }
+    if let Err(rejected) = state.private.admit(room, Some(&user), &access) {
+        return rejected.into_response();
+    }
+    if let Err(rejected) = state.directory.claimable(&user, claim.token.as_deref()) {
+        return rejected.into_response();
+    }
+    ws.protocols(Protocol::preferred(&headers))
+        .max_message_size(MAX_FRAME_BYTES)
+        .on_upgrade(move |c| handle_tweet(c, room, user, access, claim, Arc::new(state)))
+}
+
+async fn handle_tweet(
+    socket: WebSocket,
+    room: i32,
+    user: String,
+    access: RoomAccess,
+    claim: NameClaim,
+    state: Arc<BirdAppState>,
+) {
+    let protocol = Protocol::negotiated(&socket);
+    let (mut sender, mut receiver) = socket.split();
+
+    // Frames for this connection only: errors, lag notices and direct
+    // messages, which reach it through the directory.
+    let (direct, mut direct_receiver) = mpsc::channel::<RoomEvent>(16);
+    // Both were checked before the upgrade, but the name may have been
+    // claimed or the room made private since.
+    let joined = state
+        .directory
+        .register(&user, claim.token.as_deref(), direct.clone())
+        .and_then(|mailbox| {
+            // Subscribe before reading history so nothing falls in between;
+            // live messages already covered by the replay are skipped by id.
+            let (room_state, room_receiver) = state.join(room, &user, &access)?;
+            Ok((mailbox, room_state, room_receiver))
+        });
+    let (mailbox, room_state, mut room_receiver) = match joined {
+        Ok(joined) => joined,
+        Err(rejected) => {
+            let _ = sender
+                .send(close_frame(close_code::POLICY, rejected.message))
+                .await;
+            return;
+        }
+    };
+    let connected = RoomEvent::Connected {
+        token: mailbox.token.clone(),
+    };
+    let mut last_seen = 0;
+    let (replay, _) = state.replay(room, None).await;
+    let replay = replay.into_iter().map(|tweet| {
+        last_seen = tweet.id;
+        RoomEvent::Message(tweet)
+    });
+    for event in std::iter::once(connected).chain(replay) {
+        let Some(frame) = protocol.encode(&event) else {
+            continue;
+        };
+        if sender.send(frame).await.is_err() {
+            state.leave(room, &user);
+            return;
+        }
+    }
+    // Any frame from the client, pongs included, shows it's still there.
+    let missed_pongs = Arc::new(AtomicU32::new(0));
+    let heard_from = missed_pongs.clone();
+
+    let publisher = state.clone();
+    let sending_user = user.clone();
+    let mut send = tokio::spawn(async move {
+        while let Some(msg) = receiver.next().await {
+            let Ok(msg) = msg else {
+                return;
+            };
+            heard_from.store(0, Ordering::Relaxed);
+
+            let handled = publisher
+                .handle_frame(room, &room_state, &sending_user, &access, protocol, &msg)
+                .await;
+            if let Err(rejected) = handled {
+                let _ = direct.try_send(rejected.into());
+            }
+        }
+    });
+
+    let receiving = state.clone();
+    let receiving_user = user.clone();
+    let heartbeat = state.heartbeat;
+    let mut receive = tokio::spawn(async move {
+        let mut ticker = heartbeat.ticker();
+        loop {
+            let event = tokio::select! {
+                event = room_receiver.recv() => match event {
+                    Ok(event) => event,
+                    // Falling behind skips ahead rather than disconnecting.
+                    Err(RecvError::Lagged(missed)) => RoomEvent::Lagged { missed },
+                    Err(RecvError::Closed) => {
+                        let _ = sender.send(close_frame(close_code::AWAY, "Room closed")).await;
+                        break;
+                    }
+                },
+                Some(event) = direct_receiver.recv() => event,
+                _ = ticker.tick() => {
+                    let frame = heartbeat.beat(&missed_pongs);
+                    let timed_out = frame.is_err();
+                    if sender.send(frame.unwrap_or_else(|close| close)).await.is_err() || timed_out {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match &event {
+                RoomEvent::Message(tweet) if tweet.id <= last_seen => continue,
+                RoomEvent::Message(_) => {
+                    receiving.metrics.record_delivered(room, &receiving_user);
+                }
+                RoomEvent::Typing { user } if *user == receiving_user => continue,
+                RoomEvent::Ban { user } if *user == receiving_user => {
+                    let _ = sender
+                        .send(close_frame(close_code::POLICY, "Banned from this room"))
+                        .await;
+                    break;
+                }
+                _ => {}
+            }
+            if let Some(frame) = protocol.encode(&event) {
+                if sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = (&mut send) => receive.abort(),
+        _ = (&mut receive) => send.abort(),
+    };
+    state.leave(room, &user);
+}
+
+#[derive(Deserialize, Debug)]
+struct SseQuery {
+    user: String,
+    #[serde(flatten)]
+    claim: NameClaim,
+}
+
+/// Holds an SSE client's place in the room; leaving happens on drop,
+/// which is when the client goes away.
+struct SseSubscription {
+    state: BirdAppState,
+    room: RoomId,
+    user: String,
+    backlog: VecDeque<RoomEvent>,
+    receiver: Receiver<RoomEvent>,
+    direct: mpsc::Receiver<RoomEvent>,
+    _mailbox: Mailbox,
+    last_seen: u64,
+}
+
+impl Drop for SseSubscription {
+    fn drop(&mut self) {
+        self.state.leave(self.room, &self.user);
+    }
+}
+
+fn sse_event(event: &RoomEvent) -> Event {
+    let name = match event {
+        RoomEvent::Message(_) => "message",
+        RoomEvent::Join { .. } => "join",
+        RoomEvent::Leave { .. } => "leave",
+        RoomEvent::Typing { .. } => "typing",
+        RoomEvent::Ban { .. } => "ban",
+        RoomEvent::Direct(_) => "direct",
+        RoomEvent::Error { .. } => "error",
+        RoomEvent::Lagged { .. } => "lagged",
+        RoomEvent::Connected { .. } => "connected",
+    };
+    let sse = Event::default()
+        .event(name)
+        .json_data(event)
+        .expect("events serialize to JSON");
+    // Only messages carry ids, so Last-Event-ID always names a message.
+    match event {
+        RoomEvent::Message(tweet) => sse.id(tweet.id.to_string()),
+        _ => sse,
+    }
+}
+
+impl SseSubscription {
+    async fn next_event(&mut self) -> Option<Event> {
+        if let Some(event) = self.backlog.pop_front() {
+            if let RoomEvent::Message(tweet) = &event {
+                self.last_seen = tweet.id;
+            }
+            return Some(sse_event(&event));
+        }
+        loop {
+            let event = tokio::select! {
+                event = self.receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => RoomEvent::Lagged { missed },
+                    Err(RecvError::Closed) => return None,
+                },
+                Some(event) = self.direct.recv() => event,
+            };
+            match &event {
+                RoomEvent::Message(tweet) if tweet.id <= self.last_seen => continue,
+                RoomEvent::Message(tweet) => {
+                    self.last_seen = tweet.id;
+                    self.state.metrics.record_delivered(self.room, &self.user);
+                }
+                RoomEvent::Typing { user } if *user == self.user => continue,
+                RoomEvent::Ban { user } if *user == self.user => return None,
+                _ => {}
+            }
+            return Some(sse_event(&event));
+        }
+    }
+}
+
+/// The room as Server-Sent Events, for clients that can't use websockets.
+/// Reconnecting with `Last-Event-ID` resumes after that message.
+async fn sse_room(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Query(query): Query<SseQuery>,
+    Query(access): Query<RoomAccess>,
+    headers: HeaderMap,
+) -> Response {
+    if state.moderation.is_banned(room, &query.user) {
+        return Rejected::new("banned", "Banned from this room").into_response();
+    }
+    let after = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    let (sender, direct) = mpsc::channel(16);
+    let mailbox = match state
+        .directory
+        .register(&query.user, query.claim.token.as_deref(), sender)
+    {
+        Ok(mailbox) => mailbox,
+        Err(rejected) => return rejected.into_response(),
+    };
+    let receiver = match state.join(room, &query.user, &access) {
+        Ok((_, receiver)) => receiver,
+        Err(rejected) => return rejected.into_response(),
+    };
+    let connected = RoomEvent::Connected {
+        token: mailbox.token.clone(),
+    };
+    let (replay, missed) = state.replay(room, after).await;
+    let lagged = (missed > 0).then_some(RoomEvent::Lagged { missed });
+    let backlog = std::iter::once(connected)
+        .chain(lagged)
+        .chain(replay.into_iter().map(RoomEvent::Message))
+        .collect();
+    let subscription = SseSubscription {
+        state,
+        room,
+        user: query.user,
+        backlog,
+        receiver,
+        direct,
+        _mailbox: mailbox,
+        last_seen: after.unwrap_or_default(),
+    };
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let event = subscription.next_event().await?;
+        Some((Ok::<_, Infallible>(event), subscription))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct PostedMessage {
+    user: String,
+    #[serde(flatten)]
+    message: TweetInput,
+    #[serde(flatten)]
+    access: RoomAccess,
+}
+
+/// Sends a message over plain HTTP, subject to the same moderation as
+/// websocket frames.
+async fn post_message(
+    State(state): State<BirdAppState>,
+    Path(room): Path<RoomId>,
+    Json(posted): Json<PostedMessage>,
+) -> Response {
+    if state.moderation.is_banned(room, &posted.user) {
+        return Rejected::new("banned", "Banned from this room").into_response();
+    }
+    if let Err(rejected) = state
+        .private
+        .admit(room, Some(&posted.user), &posted.access)
+    {
+        return rejected.into_response();
+    }
+    let message = match posted.message.validate() {
+        Ok(message) => message,
+        Err(rejected) => return rejected.into_response(),
+    };
+    if let Err(rejected) = state.moderation.check(room, &posted.user, Some(&message)) {
+        return rejected.into_response();
+    }
+    match state
+        .publish(room, posted.user, &posted.access, message)
+        .await
+    {
+        Ok(tweet) => (StatusCode::CREATED, Json(RoomEvent::Message(tweet))).into_response(),
+        Err(rejected) => rejected.into_response(),
+    }
+}
+
+/// Sends a direct message over plain HTTP, from the user whose open
+/// connection the `Authorization: Bearer` token names (see the
+/// `connected` frame). The recipient has to be connected too.
+async fn post_direct(
+    State(state): State<BirdAppState>,
+    Path(to): Path<String>,
+    headers: HeaderMap,
+    Json(message): Json<TweetInput>,
+) -> Response {
+    let from = headers
+        .typed_get::<Authorization<Bearer>>()
+        .and_then(|auth| state.directory.user(auth.token()));
+    let Some(from) = from else {
+        return Rejected::new("unauthorized", "Send from an open connection's token")
+            .into_response();
+    };
+    let sent = message.validate().and_then(|message| {
+        state.moderation.check_direct(&from, &message)?;
+        state.send_direct(from, to, message)
+    });
+    match sent {
+        Ok(direct) => (StatusCode::CREATED, Json(RoomEvent::Direct(direct))).into_response(),
+        Err(rejected) => rejected.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn tweet(id: u64) -> Tweet {
+        Tweet {
+            id,
+            user: "elf".to_string(),
+            message: TweetInput {
+                message: format!("message {}", id),
+            },
+            ts: 0,
+        }
+    }
+
+    fn ids(page: anyhow::Result<Vec<Tweet>>) -> Vec<u64> {
+        page.unwrap().iter().map(|tweet| tweet.id).collect()
+    }
+
+    #[test]
+    fn memory_store_pages_backwards_oldest_first() {
+        block_on(async {
+            let store = MemoryMessageStore::new(5);
+            for id in 1..=8 {
+                store.append(id as RoomId % 2, &tweet(id)).await.unwrap();
+            }
+            assert_eq!(ids(store.page(0, None, 10).await), [2, 4, 6, 8]);
+            assert_eq!(ids(store.page(1, None, 2).await), [5, 7]);
+            assert_eq!(ids(store.page(1, Some(5), 10).await), [1, 3]);
+            assert_eq!(ids(store.page(1, Some(1), 10).await), Vec::<u64>::new());
+            assert_eq!(ids(store.page(9, None, 10).await), Vec::<u64>::new());
+        });
+    }
+
+    #[test]
+    fn memory_store_keeps_the_latest_messages_per_room() {
+        block_on(async {
+            let store = MemoryMessageStore::new(3);
+            for id in 1..=5 {
+                store.append(1, &tweet(id)).await.unwrap();
+            }
+            store.append(2, &tweet(6)).await.unwrap();
+            assert_eq!(ids(store.page(1, None, 10).await), [3, 4, 5]);
+            assert_eq!(ids(store.page(1, Some(4), 10).await), [3]);
+            assert_eq!(ids(store.page(2, None, 10).await), [6]);
+        });
+    }
+
+    fn state(history_size: usize) -> BirdAppState {
+        BirdAppState::new(
+            Arc::new(MemoryMessageStore::new(history_size + 1)),
+            history_size,
+            Arc::new(Metrics::default()),
+        )
+    }
+
+    async fn post(state: &BirdAppState, room: RoomId, message: &str) -> Tweet {
+        let message = TweetInput {
+            message: message.to_string(),
+        };
+        state
+            .publish(room, "elf".to_string(), &RoomAccess::default(), message)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn replay_resumes_after_the_last_seen_message() {
+        block_on(async {
+            let state = state(10);
+            for i in 0..4 {
+                post(&state, 1, &format!("one {}", i)).await;
+                post(&state, 2, &format!("two {}", i)).await;
+            }
+            let replayed = |tweets: Vec<Tweet>| tweets.iter().map(|t| t.id).collect::<Vec<_>>();
+            assert_eq!(replayed(state.replay(1, None).await.0), [1, 3, 5, 7]);
+            assert_eq!(replayed(state.replay(1, Some(3)).await.0), [5, 7]);
+            assert_eq!(
+                replayed(state.replay(2, Some(8)).await.0),
+                Vec::<u64>::new()
+            );
+        });
+    }
+
+    /// A store whose writes always fail.
+    #[derive(Debug)]
+    struct BrokenStore;
+
+    #[async_trait]
+    impl MessageStore for BrokenStore {
+        async fn append(&self, _: RoomId, _: &Tweet) -> anyhow::Result<()> {
+            anyhow::bail!("disk full")
+        }
+
+        async fn page(&self, _: RoomId, _: Option<u64>, _: usize) -> anyhow::Result<Vec<Tweet>> {
+            Ok(Vec::new())
+        }
+
+        async fn last_id(&self) -> anyhow::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    async fn refused(state: &BirdAppState, room_state: &RoomState, frame: Message) -> String {
+        let rejected = state
+            .handle_frame(
+                1,
+                room_state,
+                "elf",
+                &RoomAccess::default(),
+                Protocol::V2,
+                &frame,
+            )
+            .await
+            .unwrap_err();
+        match Protocol::V2.encode(&rejected.into()) {
+            Some(Text(text)) => text,
+            frame => panic!("expected a text frame, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn refused_frames_come_back_as_error_frames() {
+        block_on(async {
+            let state = state(10);
+            let (room_state, _receiver) = state.join(1, "elf", &RoomAccess::default()).unwrap();
+            let message = |text: &str| Text(json!({ "message": text }).to_string());
+
+            assert!(refused(&state, &room_state, Text("{".to_string()))
+                .await
+                .starts_with(r#"{"type":"error","code":"invalid","message":"Error parsing frame"#));
+            assert_eq!(
+                refused(
+                    &state,
+                    &room_state,
+                    message(&"x".repeat(MAX_MESSAGE_LEN + 1))
+                )
+                .await,
+                r#"{"type":"error","code":"too_long","message":"Message length cannot be over 128"}"#
+            );
+            let direct = Text(r#"{"type":"direct","to":"nobody","message":"hi"}"#.to_string());
+            assert_eq!(
+                refused(&state, &room_state, direct).await,
+                r#"{"type":"error","code":"unknown_user","message":"nobody is not connected"}"#
+            );
+
+            // Frames the protocol doesn't use are ignored rather than refused.
+            let binary = Binary(vec![1, 2, 3]);
+            assert!(state
+                .handle_frame(
+                    1,
+                    &room_state,
+                    "elf",
+                    &RoomAccess::default(),
+                    Protocol::V2,
+                    &binary
+                )
+                .await
+                .is_ok());
+            // bird.v1 clients never see error frames.
+            assert!(Protocol::V1
+                .encode(&Rejected::new("invalid", "nope").into())
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn rate_limited_frames_are_refused() {
+        block_on(async {
+            let state = state(10);
+            let (room_state, _receiver) = state.join(1, "elf", &RoomAccess::default()).unwrap();
+            let typing = Text(r#"{"type":"typing"}"#.to_string());
+            let burst = state.moderation.burst as usize;
+            for _ in 0..burst {
+                assert!(state
+                    .handle_frame(
+                        1,
+                        &room_state,
+                        "elf",
+                        &RoomAccess::default(),
+                        Protocol::V2,
+                        &typing
+                    )
+                    .await
+                    .is_ok());
+            }
+            assert_eq!(
+                refused(&state, &room_state, typing).await,
+                r#"{"type":"error","code":"rate_limited","message":"Slow down"}"#
+            );
+        });
+    }
+
+    #[test]
+    fn messages_that_fail_to_store_are_refused() {
+        block_on(async {
+            let state = BirdAppState::new(Arc::new(BrokenStore), 10, Arc::new(Metrics::default()));
+            let (room_state, mut receiver) = state.join(1, "elf", &RoomAccess::default()).unwrap();
+            let _join = receiver.try_recv().unwrap();
+            let message = Text(r#"{"message":"hello"}"#.to_string());
+            assert_eq!(
+                refused(&state, &room_state, message).await,
+                r#"{"type":"error","code":"not_stored","message":"Failed to store the message"}"#
+            );
+            assert!(receiver.try_recv().is_err(), "nothing was broadcast");
+        });
+    }
+
+    #[test]
+    fn resuming_past_the_replay_window_reports_a_lag() {
+        block_on(async {
+            let state = state(3);
+            for i in 1..=6 {
+                post(&state, 1, &format!("message {}", i)).await;
+            }
+            let (replay, missed) = state.replay(1, Some(1)).await;
+            assert_eq!(replay.iter().map(|t| t.id).collect::<Vec<_>>(), [4, 5, 6]);
+            assert!(missed > 0);
+            assert_eq!(state.replay(1, Some(3)).await.1, 0);
+            assert_eq!(state.replay(1, None).await.1, 0);
+
+            let mut headers = HeaderMap::new();
+            headers.insert("last-event-id", "1".parse().unwrap());
+            let response = sse_room(
+                State(state.clone()),
+                Path(1),
+                Query(SseQuery {
+                    user: "elf".to_string(),
+                    claim: NameClaim::default(),
+                }),
+                Query(RoomAccess::default()),
+                headers,
+            )
+            .await;
+            let mut body = response.into_body();
+            let mut events = String::new();
+            // Connected, lagged, then the three replayed messages.
+            for _ in 0..5 {
+                let chunk = axum::body::HttpBody::data(&mut body)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                events.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+            let lagged = events.find("event:lagged").expect("a lagged event");
+            assert!(events.find("event:connected").unwrap() < lagged);
+            assert!(lagged < events.find("id:4").unwrap());
+            assert!(!events.contains("id:3"));
+        });
+    }
+
+    #[test]
+    fn protocol_follows_our_preference_over_the_clients_order() {
+        let offer = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("sec-websocket-protocol", value.parse().unwrap());
+            Protocol::preferred(&headers)
+        };
+        assert_eq!(offer("bird.v1, bird.v2.msgpack"), Some("bird.v2.msgpack"));
+        assert_eq!(offer("bird.v1,bird.v2"), Some("bird.v2"));
+        assert_eq!(offer("chat, bird.v1"), Some("bird.v1"));
+        assert_eq!(offer("chat"), None);
+        assert_eq!(Protocol::preferred(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn idle_rate_limit_buckets_are_dropped() {
+        let moderation = Moderation::from_env();
+        assert!(moderation.take_token("alice"));
+        let start = Instant::now();
+        moderation.drop_full_buckets(start);
+        assert!(moderation.buckets.lock().unwrap().contains_key("alice"));
+
+        let refilled = start + Duration::from_secs_f64(1.0 / moderation.rate_per_sec);
+        moderation.drop_full_buckets(refilled);
+        assert!(moderation.buckets.lock().unwrap().is_empty());
+    }
+
+    async fn body_text(response: Response) -> String {
+        let mut body = response.into_body();
+        let mut text = String::new();
+        while let Some(chunk) = axum::body::HttpBody::data(&mut body).await {
+            text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        }
+        text
+    }
+
+    #[test]
+    fn private_room_counts_are_not_public() {
+        block_on(async {
+            let state = state(1);
+            state.private.create(2, "owner".to_string(), Some("secret"));
+            {
+                let mut counts = state.metrics.counts.lock().unwrap();
+                for room in [1, 2] {
+                    counts.rooms.insert(
+                        room,
+                        Counters {
+                            delivered: 3,
+                            sent: 1,
+                        },
+                    );
+                }
+            }
+
+            let exposed = body_text(metrics(State(state.clone())).await.into_response()).await;
+            assert!(exposed.contains("room=\"1\""));
+            assert!(!exposed.contains("room=\"2\""));
+
+            let views_of = |room, session: Option<String>| {
+                views(
+                    State(state.clone()),
+                    Query(ViewsQuery {
+                        room: Some(room),
+                        user: None,
+                    }),
+                    Query(RoomAccess { session }),
+                )
+            };
+            assert_eq!(body_text(views_of(1, None).await).await, "3");
+            assert_eq!(views_of(2, None).await.status(), StatusCode::FORBIDDEN);
+            let session = state
+                .private
+                .open_session(2, "elf", Some("secret"), None)
+                .unwrap();
+            assert_eq!(body_text(views_of(2, Some(session)).await).await, "3");
+        });
+    }
+
+    #[test]
+    fn admission_is_checked_again_once_a_room_turns_private() {
+        block_on(async {
+            let state = state(1);
+            let request = PrivateRoomRequest {
+                owner: "owner".to_string(),
+                secret: Some("secret".to_string()),
+            };
+            let response = make_private(State(state.clone()), Path(2), Json(request)).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert!(state.publishing.lock().unwrap().is_empty());
+
+            // As if admitted while the room was still public.
+            let outsider = RoomAccess::default();
+            let joined = state.join(2, "elf", &outsider);
+            assert_eq!(joined.err().unwrap().code, "private");
+            let message = TweetInput {
+                message: "hi".to_string(),
+            };
+            let published = state
+                .publish(2, "elf".to_string(), &outsider, message.clone())
+                .await;
+            assert_eq!(published.unwrap_err().code, "private");
+
+            let session = state
+                .private
+                .open_session(2, "elf", Some("secret"), None)
+                .unwrap();
+            let member = RoomAccess {
+                session: Some(session),
+            };
+            assert!(state.join(2, "elf", &member).is_ok());
+            let published = state.publish(2, "elf".to_string(), &member, message).await;
+            assert!(published.is_ok());
+        });
+    }
+
+    #[test]
+    fn a_connected_name_takes_one_of_its_tokens() {
+        let directory = Arc::new(Directory::default());
+        let connect = |claim: Option<&str>| directory.register("alice", claim, mpsc::channel(1).0);
+        let first = connect(None).unwrap();
+        assert_eq!(connect(None).unwrap_err().code, "name_taken");
+        assert_eq!(connect(Some("guess")).unwrap_err().code, "name_taken");
+        assert_eq!(
+            directory.claimable("alice", None).unwrap_err().code,
+            "name_taken"
+        );
+
+        let second = connect(Some(&first.token)).unwrap();
+        drop(first);
+        assert!(connect(None).is_err());
+        drop(second);
+        assert!(connect(None).is_ok());
+    }
 }
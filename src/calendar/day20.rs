@@ -1,50 +1,485 @@
 use super::AppError;
-use axum::{body::Bytes, http::StatusCode, routing::post, Router};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{DefaultBodyLimit, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use futures_util::stream;
 use git2::{Commit, Object, Repository};
-use std::io::Cursor;
-use tar::Archive;
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    fmt::{self, Write},
+    io::{self, Cursor, Read},
+    path::Path,
+};
+use tar::{Archive, EntryType};
 use tempfile::tempdir;
+use tokio::sync::mpsc;
+use zip::ZipArchive;
+
+const MAX_MANIFEST_BYTES: usize = 256 * 1024 * 1024;
+/// Most an archive may unpack to across all of its entries, so a small
+/// upload can't decompress into an unbounded amount of data.
+const MAX_UNPACKED_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_LARGEST: usize = 10;
+const MAX_LARGEST: usize = 1000;
 
 pub fn router() -> Router {
     let regular = Router::new()
         .route("/archive_files", post(count_files_in_archive))
-        .route("/archive_files_size", post(file_sizes_sum));
+        .route("/archive_files_size", post(file_sizes_sum))
+        .route(
+            "/manifest",
+            post(manifest).layer(DefaultBodyLimit::max(MAX_MANIFEST_BYTES)),
+        );
     let bonus = Router::new().route("/cookie", post(find_commit_author));
     Router::new().nest("/20", regular).nest("/20", bonus)
 }
 
-async fn count_files_in_archive(payload: Bytes) -> Result<String, AppError> {
-    let cursor = Cursor::new(payload.to_vec());
-    let mut archive = Archive::new(cursor);
-    Ok(archive.entries().unwrap().count().to_string())
-}
-
-async fn file_sizes_sum(payload: Bytes) -> Result<String, AppError> {
-    let cursor = Cursor::new(payload.to_vec());
-    let mut archive = Archive::new(cursor);
-    Ok(archive
-        .entries()
-        .unwrap()
-        .map(|f| f.unwrap().size())
-        .sum::<u64>()
-        .to_string())
-}
-
-async fn find_commit_author(payload: Bytes) -> Result<(StatusCode, String), AppError> {
-    let cursor = Cursor::new(payload.to_vec());
-    let mut archive = Archive::new(cursor);
-    let dir = tempdir().unwrap();
-    archive.unpack(dir.path()).unwrap();
-    let repo = Repository::open(dir.path()).expect("Unable to open repo");
-    let commit = repo
-        .find_branch("christmas", git2::BranchType::Local)
-        .expect("branch does not exist")
-        .get()
-        .peel_to_commit()
-        .expect("could not extract commit");
-    let cookie = find_cookie_commit(0, &commit, &repo).1.unwrap();
-
-    dbg!(cookie.author().name().unwrap().to_string());
+/// Archive formats, told apart by their magic bytes. Compressed streams
+/// are assumed to hold a tar. The last few are only recognised so the
+/// error can say what was uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    TarGz,
+    TarBz2,
+    TarXz,
+    TarZst,
+    Zip,
+    SevenZip,
+    Rar,
+    Unknown,
+}
+
+impl Format {
+    fn sniff(bytes: &[u8]) -> Self {
+        match bytes {
+            [0x1f, 0x8b, ..] => Format::TarGz,
+            [b'B', b'Z', b'h', ..] => Format::TarBz2,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Format::TarXz,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Format::TarZst,
+            [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Format::Zip,
+            [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c, ..] => Format::SevenZip,
+            [b'R', b'a', b'r', b'!', 0x1a, 0x07, ..] => Format::Rar,
+            bytes if is_tar_header(bytes) => Format::Tar,
+            _ => Format::Unknown,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::TarGz => "tar.gz",
+            Format::TarBz2 => "tar.bz2",
+            Format::TarXz => "tar.xz",
+            Format::TarZst => "tar.zst",
+            Format::Zip => "zip",
+            Format::SevenZip => "7z",
+            Format::Rar => "rar",
+            Format::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A ustar header, an old-style header with a valid checksum, or the
+/// zeroed block of an empty archive.
+fn is_tar_header(bytes: &[u8]) -> bool {
+    let Some(header) = bytes.get(..512) else {
+        return false;
+    };
+    if &header[257..262] == b"ustar" || header.iter().all(|&b| b == 0) {
+        return true;
+    }
+    let stored = std::str::from_utf8(&header[148..156])
+        .ok()
+        .map(|field| field.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|field| u32::from_str_radix(field, 8).ok());
+    let computed = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                u32::from(b' ')
+            } else {
+                u32::from(b)
+            }
+        })
+        .sum::<u32>();
+    stored == Some(computed)
+}
+
+/// An unsupported or unreadable upload, answered with a 400 naming the
+/// format that was detected.
+#[derive(Serialize, Debug, Clone)]
+struct ArchiveError {
+    format: &'static str,
+    message: String,
+}
+
+impl ArchiveError {
+    fn new(format: Format, message: impl fmt::Display) -> Self {
+        Self {
+            format: format.name(),
+            message: message.to_string(),
+        }
+    }
+
+    fn corrupt(format: Format, error: impl std::error::Error + 'static) -> Self {
+        if is_too_large(&error) {
+            return Self::new(format, TooLarge);
+        }
+        Self::new(format, format!("Corrupt {} archive: {}", format, error))
+    }
+}
+
+/// Hitting the cap on unpacked bytes, carried inside an `io::Error`.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Archive unpacks to more than {} bytes",
+            MAX_UNPACKED_BYTES
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Whether `TooLarge` is somewhere down the error's chain. `io::Error`s
+/// skip their own payload in `source`, so those are unwrapped by hand.
+fn is_too_large(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut next = Some(error);
+    while let Some(error) = next {
+        if error.is::<TooLarge>() {
+            return true;
+        }
+        next = match error.downcast_ref::<io::Error>() {
+            Some(error) => error
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => error.source(),
+        };
+    }
+    false
+}
+
+/// Fails with `TooLarge` once more than `remaining` bytes have been read
+/// through it.
+struct Capped<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.remaining = self
+            .remaining
+            .checked_sub(read as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, TooLarge))?;
+        Ok(read)
+    }
+}
+
+impl IntoResponse for ArchiveError {
+    fn into_response(self) -> Response {
+        (StatusCode::BAD_REQUEST, Json(self)).into_response()
+    }
+}
+
+/// An upload opened according to its sniffed format.
+enum Opened {
+    Tar(Format, Archive<Box<dyn Read + Send>>),
+    Zip(ZipArchive<Cursor<Bytes>>),
+}
+
+fn open(payload: Bytes) -> Result<Opened, ArchiveError> {
+    let format = Format::sniff(&payload);
+    let cursor = Cursor::new(payload);
+    let reader: Box<dyn Read + Send> = match format {
+        Format::Tar => Box::new(cursor),
+        Format::TarGz => Box::new(flate2::read::GzDecoder::new(cursor)),
+        Format::TarBz2 => Box::new(bzip2::read::BzDecoder::new(cursor)),
+        Format::TarXz => Box::new(xz2::read::XzDecoder::new(cursor)),
+        Format::TarZst => Box::new(
+            zstd::stream::read::Decoder::new(cursor)
+                .map_err(|e| ArchiveError::corrupt(format, e))?,
+        ),
+        Format::Zip => {
+            return ZipArchive::new(cursor)
+                .map(Opened::Zip)
+                .map_err(|e| ArchiveError::corrupt(format, e))
+        }
+        Format::SevenZip | Format::Rar | Format::Unknown => {
+            return Err(ArchiveError::new(
+                format,
+                format!("Unsupported archive format: {}", format),
+            ))
+        }
+    };
+    let capped = Capped {
+        inner: reader,
+        remaining: MAX_UNPACKED_BYTES,
+    };
+    Ok(Opened::Tar(format, Archive::new(Box::new(capped))))
+}
+
+impl Opened {
+    /// Sizes of every entry, in archive order.
+    fn sizes(self) -> Result<Vec<u64>, ArchiveError> {
+        match self {
+            Opened::Tar(format, mut archive) => archive
+                .entries()
+                .map_err(|e| ArchiveError::corrupt(format, e))?
+                .map(|entry| {
+                    entry
+                        .map(|entry| entry.size())
+                        .map_err(|e| ArchiveError::corrupt(format, e))
+                })
+                .collect(),
+            Opened::Zip(mut archive) => (0..archive.len())
+                .map(|i| {
+                    archive
+                        .by_index_raw(i)
+                        .map(|entry| entry.size())
+                        .map_err(|e| ArchiveError::corrupt(Format::Zip, e))
+                })
+                .collect(),
+        }
+    }
+
+    /// Describes each entry matching `glob`, hashing file contents as it
+    /// goes. Stops early once `emit` returns false.
+    fn manifest(
+        self,
+        glob: Option<&Pattern>,
+        mut emit: impl FnMut(ManifestEntry) -> bool,
+    ) -> Result<(), ArchiveError> {
+        let selected = |path: &str| glob.map_or(true, |glob| glob.matches_with(path, GLOB_OPTIONS));
+        match self {
+            Opened::Tar(format, mut archive) => {
+                let corrupt = |e: io::Error| ArchiveError::corrupt(format, e);
+                for entry in archive.entries().map_err(corrupt)? {
+                    let mut entry = entry.map_err(corrupt)?;
+                    let path = entry
+                        .path()
+                        .map_err(corrupt)?
+                        .to_string_lossy()
+                        .into_owned();
+                    if !selected(&path) {
+                        continue;
+                    }
+                    let header = entry.header();
+                    let kind = match header.entry_type() {
+                        EntryType::Regular | EntryType::Continuous => EntryKind::File,
+                        EntryType::Directory => EntryKind::Dir,
+                        EntryType::Symlink => EntryKind::Symlink,
+                        EntryType::Link => EntryKind::Hardlink,
+                        _ => EntryKind::Other,
+                    };
+                    let owner = Owner {
+                        uid: header.uid().ok(),
+                        gid: header.gid().ok(),
+                        user: header.username().ok().flatten().map(str::to_string),
+                        group: header.groupname().ok().flatten().map(str::to_string),
+                    };
+                    let mode = header.mode().ok().map(format_mode);
+                    let mtime = header.mtime().ok().map(|mtime| mtime as i64);
+                    let link_target = entry
+                        .link_name()
+                        .ok()
+                        .flatten()
+                        .map(|target| target.to_string_lossy().into_owned());
+                    let size = entry.size();
+                    let sha256 = match kind {
+                        EntryKind::File => Some(sha256_hex(&mut entry).map_err(corrupt)?),
+                        _ => None,
+                    };
+                    let described = ManifestEntry {
+                        path,
+                        kind,
+                        size,
+                        mode,
+                        mtime,
+                        owner: Some(owner),
+                        link_target,
+                        sha256,
+                    };
+                    if !emit(described) {
+                        break;
+                    }
+                }
+            }
+            Opened::Zip(mut archive) => {
+                let corrupt = |e: zip::result::ZipError| ArchiveError::corrupt(Format::Zip, e);
+                let mut remaining = MAX_UNPACKED_BYTES;
+                for i in 0..archive.len() {
+                    let file = archive.by_index(i).map_err(corrupt)?;
+                    let path = file.name().to_string();
+                    if !selected(&path) {
+                        continue;
+                    }
+                    let unix_mode = file.unix_mode();
+                    let kind = if file.is_dir() {
+                        EntryKind::Dir
+                    } else if unix_mode.is_some_and(|mode| mode & 0o170000 == 0o120000) {
+                        EntryKind::Symlink
+                    } else {
+                        EntryKind::File
+                    };
+                    let modified = file.last_modified();
+                    let mtime = chrono::NaiveDate::from_ymd_opt(
+                        modified.year().into(),
+                        modified.month().into(),
+                        modified.day().into(),
+                    )
+                    .and_then(|date| {
+                        date.and_hms_opt(
+                            modified.hour().into(),
+                            modified.minute().into(),
+                            modified.second().into(),
+                        )
+                    })
+                    .map(|mtime| mtime.and_utc().timestamp());
+                    let size = file.size();
+                    let mut file = Capped {
+                        inner: file,
+                        remaining,
+                    };
+                    let (sha256, link_target) = match kind {
+                        EntryKind::File => (
+                            Some(sha256_hex(&mut file).map_err(|e| corrupt(e.into()))?),
+                            None,
+                        ),
+                        EntryKind::Symlink => {
+                            let mut target = String::new();
+                            file.read_to_string(&mut target)
+                                .map_err(|e| corrupt(e.into()))?;
+                            (None, Some(target))
+                        }
+                        _ => (None, None),
+                    };
+                    remaining = file.remaining;
+                    let described = ManifestEntry {
+                        path,
+                        kind,
+                        size,
+                        mode: unix_mode.map(format_mode),
+                        mtime,
+                        owner: None,
+                        link_target,
+                        sha256,
+                    };
+                    if !emit(described) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts into `dst`. Zip entries are written by hand rather than
+    /// with `ZipArchive::extract`, so they count towards the size cap.
+    fn unpack(self, dst: &Path) -> Result<(), ArchiveError> {
+        match self {
+            Opened::Tar(format, mut archive) => archive
+                .unpack(dst)
+                .map_err(|e| ArchiveError::corrupt(format, e)),
+            Opened::Zip(mut archive) => {
+                let corrupt = |e: io::Error| ArchiveError::corrupt(Format::Zip, e);
+                let mut remaining = MAX_UNPACKED_BYTES;
+                for i in 0..archive.len() {
+                    let file = archive
+                        .by_index(i)
+                        .map_err(|e| ArchiveError::corrupt(Format::Zip, e))?;
+                    let Some(path) = file.enclosed_name().map(|path| dst.join(path)) else {
+                        return Err(ArchiveError::new(
+                            Format::Zip,
+                            format!("Invalid path in zip archive: {}", file.name()),
+                        ));
+                    };
+                    if file.is_dir() {
+                        std::fs::create_dir_all(&path).map_err(corrupt)?;
+                        continue;
+                    }
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent).map_err(corrupt)?;
+                    }
+                    let mut file = Capped {
+                        inner: file,
+                        remaining,
+                    };
+                    let mut out = std::fs::File::create(&path).map_err(corrupt)?;
+                    io::copy(&mut file, &mut out).map_err(corrupt)?;
+                    remaining = file.remaining;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn count_files_in_archive(payload: Bytes) -> Result<Result<String, ArchiveError>, AppError> {
+    let count = tokio::task::spawn_blocking(move || {
+        Ok::<_, ArchiveError>(open(payload)?.sizes()?.len().to_string())
+    });
+    Ok(count.await?)
+}
+
+async fn file_sizes_sum(payload: Bytes) -> Result<Result<String, ArchiveError>, AppError> {
+    let sum = tokio::task::spawn_blocking(move || {
+        Ok::<_, ArchiveError>(open(payload)?.sizes()?.iter().sum::<u64>().to_string())
+    });
+    Ok(sum.await?)
+}
+
+async fn find_commit_author(payload: Bytes) -> Result<Response, AppError> {
+    tokio::task::spawn_blocking(move || cookie_author(payload)).await?
+}
+
+/// Unpacks the repository and walks it, which is all blocking work.
+fn cookie_author(payload: Bytes) -> Result<Response, AppError> {
+    let archive = match open(payload) {
+        Ok(archive) => archive,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let dir = tempdir()?;
+    if let Err(e) = archive.unpack(dir.path()) {
+        return Ok(e.into_response());
+    }
+    let Ok(repo) = Repository::open(dir.path()) else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "Archive does not contain a git repository",
+        )
+            .into_response());
+    };
+    let Ok(branch) = repo.find_branch("christmas", git2::BranchType::Local) else {
+        return Ok((StatusCode::BAD_REQUEST, "Branch christmas does not exist").into_response());
+    };
+    let commit = branch.get().peel_to_commit()?;
+    let Some(cookie) = find_cookie_commit(0, &commit, &repo)?.1 else {
+        return Ok((StatusCode::NOT_FOUND, "No cookie found").into_response());
+    };
+
     Ok((
         StatusCode::OK,
         format!(
@@ -52,23 +487,34 @@ async fn find_commit_author(payload: Bytes) -> Result<(StatusCode, String), AppE
             cookie.author().name().unwrap_or_default(),
             cookie.id()
         ),
-    ))
+    )
+        .into_response())
 }
 
 fn find_cookie_commit<'a>(
     count: u32,
     commit: &Commit<'a>,
     repo: &Repository,
-) -> (u32, Option<Commit<'a>>) {
-    let tree = commit.tree().unwrap();
+) -> Result<(u32, Option<Commit<'a>>), git2::Error> {
+    let tree = commit.tree()?;
     let mut commits: Vec<Object> = Vec::new();
-    tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+    let mut lookup_error = None;
+    let walked = tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
         if entry.name() == Some("santa.txt") {
-            commits.push(entry.clone().to_object(repo).unwrap());
+            match entry.to_object(repo) {
+                Ok(object) => commits.push(object),
+                Err(e) => {
+                    lookup_error = Some(e);
+                    return git2::TreeWalkResult::Abort;
+                }
+            }
         }
         git2::TreeWalkResult::Ok
-    })
-    .unwrap();
+    });
+    if let Some(e) = lookup_error {
+        return Err(e);
+    }
+    walked?;
     let strings: Vec<&str> = commits
         .iter()
         .filter_map(|o| o.as_blob())
@@ -77,11 +523,251 @@ fn find_cookie_commit<'a>(
         .filter(|s| s.contains("COOKIE"))
         .collect();
     if !strings.is_empty() {
-        return (count, Some(commit.clone()));
+        return Ok((count, Some(commit.clone())));
     }
-    commit
+    Ok(commit
         .parents()
         .map(|p| find_cookie_commit(count + 1, &p, repo))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
         .min_by_key(|c| c.0)
-        .unwrap_or((0, None))
+        .unwrap_or((0, None)))
+}
+
+/// Globs match whole paths, and `*` doesn't cross directories.
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Other,
+}
+
+/// Ownership as recorded in tar headers; zip archives don't carry it.
+#[derive(Serialize, Debug)]
+struct Owner {
+    uid: Option<u64>,
+    gid: Option<u64>,
+    user: Option<String>,
+    group: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ManifestEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: EntryKind,
+    size: u64,
+    /// Permission bits in octal, e.g. `"0644"`.
+    mode: Option<String>,
+    /// Seconds since the Unix epoch.
+    mtime: Option<i64>,
+    owner: Option<Owner>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+    /// Only for regular files.
+    sha256: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+struct Totals {
+    entries: u64,
+    files: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct LargestFile {
+    path: String,
+    size: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct ManifestSummary {
+    format: &'static str,
+    totals: Totals,
+    /// File counts and bytes by lowercased extension, `""` for none.
+    extensions: BTreeMap<String, Totals>,
+    largest: Vec<LargestFile>,
+}
+
+/// Running totals over the entries seen so far, keeping only the `top`
+/// largest files.
+struct Tally {
+    format: Format,
+    top: usize,
+    totals: Totals,
+    extensions: BTreeMap<String, Totals>,
+    largest: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl Tally {
+    fn new(format: Format, top: usize) -> Self {
+        Self {
+            format,
+            top,
+            totals: Totals::default(),
+            extensions: BTreeMap::new(),
+            largest: BinaryHeap::new(),
+        }
+    }
+
+    fn add(&mut self, entry: &ManifestEntry) {
+        self.totals.entries += 1;
+        if entry.kind != EntryKind::File {
+            return;
+        }
+        self.totals.files += 1;
+        self.totals.bytes += entry.size;
+        let extension = Path::new(&entry.path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let totals = self.extensions.entry(extension).or_default();
+        totals.entries += 1;
+        totals.files += 1;
+        totals.bytes += entry.size;
+        if self.top > 0 {
+            self.largest.push(Reverse((entry.size, entry.path.clone())));
+            if self.largest.len() > self.top {
+                self.largest.pop();
+            }
+        }
+    }
+
+    fn summary(self) -> ManifestSummary {
+        ManifestSummary {
+            format: self.format.name(),
+            totals: self.totals,
+            extensions: self.extensions,
+            largest: self
+                .largest
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Reverse((size, path))| LargestFile { path, size })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+    #[serde(flatten)]
+    summary: ManifestSummary,
+}
+
+/// One line of the NDJSON response: every entry, then the summary, or an
+/// error if the archive turns out to be corrupt part way through.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ManifestLine {
+    Entry(ManifestEntry),
+    Summary(ManifestSummary),
+    Error(ArchiveError),
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestQuery {
+    path_glob: Option<String>,
+    /// How many of the largest files to list.
+    top: Option<usize>,
+    format: Option<String>,
+}
+
+fn format_mode(mode: u32) -> String {
+    format!("{:04o}", mode & 0o7777)
+}
+
+fn sha256_hex(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    let mut hex_str = String::new();
+    for byte in hasher.finalize() {
+        write!(&mut hex_str, "{:02x}", byte).expect("Unable to write to string");
+    }
+    Ok(hex_str)
+}
+
+/// Lists every entry of an archive with its metadata and SHA-256, plus
+/// per-extension totals and the largest files. `?format=ndjson` (or an
+/// `Accept: application/x-ndjson` header) streams it line by line instead.
+async fn manifest(
+    Query(query): Query<ManifestQuery>,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> Response {
+    let glob = match query.path_glob.as_deref().map(Pattern::new).transpose() {
+        Ok(glob) => glob,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid path_glob: {}", e)).into_response()
+        }
+    };
+    let top = query.top.unwrap_or(DEFAULT_LARGEST).min(MAX_LARGEST);
+    let ndjson = query.format.as_deref() == Some("ndjson")
+        || headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/x-ndjson"));
+
+    let format = Format::sniff(&payload);
+    let archive = match open(payload) {
+        Ok(archive) => archive,
+        Err(e) => return e.into_response(),
+    };
+
+    if ndjson {
+        let (lines, receiver) = mpsc::channel::<ManifestLine>(64);
+        tokio::task::spawn_blocking(move || {
+            let mut tally = Tally::new(format, top);
+            let walked = archive.manifest(glob.as_ref(), |entry| {
+                tally.add(&entry);
+                lines.blocking_send(ManifestLine::Entry(entry)).is_ok()
+            });
+            let last = match walked {
+                Ok(()) => ManifestLine::Summary(tally.summary()),
+                Err(e) => ManifestLine::Error(e),
+            };
+            let _ = lines.blocking_send(last);
+        });
+        let body = stream::unfold(receiver, |mut receiver| async move {
+            let line = receiver.recv().await?;
+            let mut json = serde_json::to_vec(&line).expect("manifest lines serialize to JSON");
+            json.push(b'\n');
+            Some((Ok::<_, io::Error>(json), receiver))
+        });
+        return (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            StreamBody::new(body),
+        )
+            .into_response();
+    }
+
+    let collected = tokio::task::spawn_blocking(move || {
+        let mut tally = Tally::new(format, top);
+        let mut entries = Vec::new();
+        archive.manifest(glob.as_ref(), |entry| {
+            tally.add(&entry);
+            entries.push(entry);
+            true
+        })?;
+        Ok::<_, ArchiveError>(Manifest {
+            entries,
+            summary: tally.summary(),
+        })
+    })
+    .await;
+    match collected {
+        Ok(Ok(manifest)) => Json(manifest).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
 }
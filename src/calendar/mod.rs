@@ -1,6 +1,7 @@
 use axum::{http::StatusCode, response::IntoResponse, Router};
 use sqlx::PgPool;
 
+mod assets;
 mod day0;
 mod day1;
 mod day10;
@@ -58,15 +59,16 @@ pub(crate) fn router(pool: PgPool) -> Router {
         .nest("/", day9::router())
         .nest("/", day10::router())
         .nest("/", day11::router())
-        .nest("/", day12::router())
+        .nest("/", day12::router(pool.clone()))
         .nest("/", day13::router(pool.clone()))
         .nest("/", day14::router())
         .nest("/", day15::router())
         .nest("/", day16::router())
         .nest("/", day17::router())
         .nest("/", day18::router(pool.clone()))
-        .nest("/", day19::router())
+        .nest("/", day19::router(pool.clone()))
         .nest("/", day20::router())
         .nest("/", day21::router())
         .nest("/", day22::router())
+        .nest("/", assets::router())
 }
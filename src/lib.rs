@@ -0,0 +1,3 @@
+//! Code shared between the server and the command-line tools in `src/bin`.
+
+pub mod breached;